@@ -0,0 +1,4 @@
+fn main() {
+    // Migrations are embedded in the binary, so rebuild when they change.
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,3 @@
+ALTER TABLE facilities
+    ALTER COLUMN latitude TYPE REAL,
+    ALTER COLUMN longitude TYPE REAL;
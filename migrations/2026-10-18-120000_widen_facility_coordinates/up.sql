@@ -0,0 +1,5 @@
+-- Go through text so stored REAL values keep their shortest decimal representation
+-- instead of picking up binary noise when widened.
+ALTER TABLE facilities
+    ALTER COLUMN latitude TYPE DOUBLE PRECISION USING latitude::text::double precision,
+    ALTER COLUMN longitude TYPE DOUBLE PRECISION USING longitude::text::double precision;
@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS facilities_location_idx;
+ALTER TABLE facilities DROP COLUMN IF EXISTS location;
@@ -0,0 +1,21 @@
+-- PostGIS is optional. Without it, spatial filters fall back to the plain latitude and longitude columns.
+-- That includes when the package is installed but this role isn't allowed to create the extension,
+-- as PostGIS isn't a trusted extension.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'postgis') THEN
+        IF NOT EXISTS (SELECT 1 FROM pg_available_extensions WHERE name = 'postgis') THEN
+            RETURN;
+        END IF;
+        BEGIN
+            CREATE EXTENSION postgis;
+        EXCEPTION WHEN insufficient_privilege THEN
+            RAISE NOTICE 'not allowed to create the postgis extension, so facilities get no location column';
+            RETURN;
+        END;
+    END IF;
+    EXECUTE 'ALTER TABLE facilities ADD COLUMN location geography(Point, 4326)
+        GENERATED ALWAYS AS (ST_SetSRID(ST_MakePoint(longitude, latitude), 4326)::geography) STORED';
+    EXECUTE 'CREATE INDEX facilities_location_idx ON facilities USING GIST (location)';
+END
+$$;
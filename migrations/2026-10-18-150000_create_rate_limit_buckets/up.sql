@@ -0,0 +1,40 @@
+-- Token buckets for rate limiting shared between replicas. Losing them in a crash only resets limits,
+-- so skip the write-ahead log.
+CREATE UNLOGGED TABLE rate_limit_buckets (
+    key TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+-- Refill a bucket for the time since it was last used and take a token from it if one is available.
+-- Returns whether a token was taken and how many are left.
+CREATE FUNCTION rate_limit_take(
+    _key TEXT,
+    _capacity DOUBLE PRECISION,
+    _refill_per_second DOUBLE PRECISION,
+    OUT allowed BOOLEAN,
+    OUT remaining DOUBLE PRECISION
+) AS $$
+DECLARE
+    _now TIMESTAMPTZ := clock_timestamp();
+    _tokens DOUBLE PRECISION;
+BEGIN
+    INSERT INTO rate_limit_buckets (key, tokens, updated_at)
+    VALUES (_key, _capacity, _now)
+    ON CONFLICT (key) DO UPDATE
+        SET tokens = LEAST(
+                _capacity,
+                rate_limit_buckets.tokens
+                    + EXTRACT(EPOCH FROM _now - rate_limit_buckets.updated_at) * _refill_per_second
+            ),
+            updated_at = _now
+    RETURNING tokens INTO _tokens;
+
+    allowed := _tokens >= 1;
+    IF allowed THEN
+        _tokens := _tokens - 1;
+        UPDATE rate_limit_buckets SET tokens = _tokens WHERE key = _key;
+    END IF;
+    remaining := _tokens;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1 @@
+DROP INDEX IF EXISTS facilities_location_geometry_idx;
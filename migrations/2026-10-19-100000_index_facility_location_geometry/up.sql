@@ -0,0 +1,12 @@
+-- Bounding box filters compare planar longitude/latitude envelopes against the location as a geometry,
+-- matching the plain columns' behaviour, so index that expression too.
+DO $$
+BEGIN
+    IF EXISTS (
+        SELECT 1 FROM information_schema.columns
+        WHERE table_schema = current_schema() AND table_name = 'facilities' AND column_name = 'location'
+    ) THEN
+        EXECUTE 'CREATE INDEX facilities_location_geometry_idx ON facilities USING GIST ((location::geometry))';
+    END IF;
+END
+$$;
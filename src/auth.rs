@@ -0,0 +1,293 @@
+use crate::core::{ApiKey, Scope};
+use crate::metrics;
+use crate::models;
+use crate::problem::Problem;
+use crate::rate_limit::AuthFailures;
+use crate::storage;
+use crate::{pool_error_status, AppState};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use diesel::PgConnection;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use subtle::ConstantTimeEq;
+use tracing::{debug, error, Span};
+use utoipa::ToSchema;
+
+/// Request header carrying the client's API key.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Subject of requests made with the bootstrap admin key.
+const BOOTSTRAP_SUBJECT: &str = "bootstrap";
+
+/// Who made a request and what they may do.
+///
+/// Handlers can take it as an `Extension` to record who changed what.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    /// API key ID or the bearer token's subject.
+    pub subject: String,
+    pub scope: Scope,
+}
+
+/// Request to create a new API key.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scope: Scope,
+}
+
+/// A newly created API key, including the only copy of its full token.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub token: String,
+}
+
+/// Create and store a new API key with a random ID and secret.
+pub fn create_api_key(
+    conn: &mut PgConnection,
+    new_api_key: NewApiKey,
+) -> Result<CreatedApiKey, diesel::result::Error> {
+    let id = random_hex(6);
+    let secret = random_hex(32);
+    let api_key = storage::write_api_key(
+        conn,
+        models::NewApiKey {
+            id: id.clone(),
+            name: new_api_key.name,
+            scope: new_api_key.scope.to_string(),
+            secret_hash: hash_secret(&secret),
+        },
+    )?;
+    Ok(CreatedApiKey {
+        api_key,
+        token: format!("{id}.{secret}"),
+    })
+}
+
+/// Hash an API key secret for storage or comparison.
+///
+/// Secrets are long and random, so a fast unsalted hash is enough.
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn random_hex(n_bytes: usize) -> String {
+    let mut bytes = vec![0u8; n_bytes];
+    rand::rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Middleware identifying the client from its bearer token or API key, if it sent one.
+///
+/// Requests without credentials pass through unauthenticated so the routes' scope checks can reject them.
+/// The key ID or token subject is recorded on the request's span.
+pub async fn authenticate(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let bearer_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(bearer_token);
+    let api_key = request.headers().get(API_KEY_HEADER);
+
+    // Addresses that keep failing to authenticate wait before their credentials are checked again.
+    let failures = match (
+        &state.rate_limits,
+        request.extensions().get::<ConnectInfo<SocketAddr>>(),
+    ) {
+        (Some(rate_limits), Some(ConnectInfo(addr))) => {
+            Some((&rate_limits.auth_failures, addr.ip()))
+        }
+        _ => None,
+    };
+    if let Some((failures, ip)) = failures.filter(|_| bearer_token.is_some() || api_key.is_some()) {
+        if let Some(retry_after) = failures.retry_after(ip) {
+            debug!("too many failed authentications from {ip}");
+            return failures.too_many(retry_after);
+        }
+    }
+
+    let principal = match (bearer_token, &state.jwt, api_key) {
+        (Some(token), Some(jwt), _) => match jwt.validate(token) {
+            Ok(principal) => {
+                debug!("authenticated token subject {:?}", principal.subject);
+                Span::current().record("subject", principal.subject.as_str());
+                principal
+            }
+            Err(e) => {
+                debug!("rejected bearer token: {e}");
+                return rejected(failures, invalid_bearer_token(&e.to_string()));
+            }
+        },
+        (_, _, Some(api_key)) => {
+            let Ok(api_key) = api_key.to_str() else {
+                return rejected(failures, unauthorized("malformed API key"));
+            };
+            match authenticate_api_key(&state, api_key).await {
+                Ok(principal) => {
+                    debug!("authenticated API key {:?}", principal.subject);
+                    Span::current().record("api_key_id", principal.subject.as_str());
+                    principal
+                }
+                Err(response) => return rejected(failures, response),
+            }
+        }
+        _ => return next.run(request).await,
+    };
+
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+/// The token in an Authorization header value using the Bearer scheme, whose name is case-insensitive.
+fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.split_once(' ')?;
+    let token = token.trim_start_matches(' ');
+    (scheme.eq_ignore_ascii_case("Bearer") && !token.is_empty()).then_some(token)
+}
+
+/// Count a failed authentication against the client's address, unless it failed for some other reason.
+fn rejected(failures: Option<(&AuthFailures, IpAddr)>, response: Response) -> Response {
+    if let (Some((failures, ip)), StatusCode::UNAUTHORIZED) = (failures, response.status()) {
+        failures.record(ip);
+    }
+    response
+}
+
+async fn authenticate_api_key(state: &AppState, token: &str) -> Result<Principal, Response> {
+    let is_admin_key = state
+        .admin_api_key_hash
+        .as_deref()
+        .is_some_and(|admin| hashes_match(admin, &hash_secret(token)));
+    if is_admin_key {
+        // The bootstrap admin key from settings, for creating the first stored keys.
+        return Ok(Principal {
+            subject: String::from(BOOTSTRAP_SUBJECT),
+            scope: Scope::Admin,
+        });
+    }
+
+    let Some((id, secret)) = token.split_once('.') else {
+        return Err(unauthorized("malformed API key"));
+    };
+    let secret_hash = hash_secret(secret);
+    match lookup_api_key(state, id.to_string()).await {
+        Ok(Some((api_key, stored_hash))) if hashes_match(&stored_hash, &secret_hash) => {
+            Ok(Principal {
+                subject: api_key.id,
+                scope: api_key.scope,
+            })
+        }
+        Ok(_) => Err(unauthorized("unknown API key")),
+        Err(problem) => Err(problem.into_response()),
+    }
+}
+
+/// Compare secret hashes in time independent of where they differ, so timing doesn't reveal a matching prefix.
+fn hashes_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+async fn lookup_api_key(state: &AppState, id: String) -> Result<Option<(ApiKey, String)>, Problem> {
+    let client = state.conn_pool.get().await.map_err(|e| {
+        error!("error collecting client from connection pool {e:?}");
+        Problem::new(pool_error_status(&e))
+    })?;
+    let interaction_result = metrics::timed(
+        "read_api_key",
+        client.interact(|conn| storage::read_api_key(conn, id)),
+    )
+    .await
+    .map_err(|e| {
+        error!("error interacting through connection pool {e:?}");
+        Problem::new(StatusCode::INTERNAL_SERVER_ERROR)
+    })?;
+    match interaction_result {
+        Ok(r) => Ok(Some(r)),
+        Err(diesel::result::Error::NotFound) => Ok(None),
+        Err(e) => {
+            error!("error reading API key from database {e:?}");
+            Err(Problem::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+/// Middleware only letting through clients with at least read scope.
+pub async fn require_read(request: Request, next: Next) -> Response {
+    require_scope(Scope::Read, request, next).await
+}
+
+/// Middleware only letting through clients with at least write scope.
+pub async fn require_write(request: Request, next: Next) -> Response {
+    require_scope(Scope::Write, request, next).await
+}
+
+/// Middleware only letting through clients with admin scope.
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    require_scope(Scope::Admin, request, next).await
+}
+
+async fn require_scope(required: Scope, request: Request, next: Next) -> Response {
+    match request.extensions().get::<Principal>() {
+        None => unauthorized("missing API key or bearer token"),
+        Some(principal) if !principal.scope.allows(required) => Problem::new(StatusCode::FORBIDDEN)
+            .with_detail(format!("requires {required} scope"))
+            .into_response(),
+        Some(_) => next.run(request).await,
+    }
+}
+
+fn invalid_bearer_token(detail: &str) -> Response {
+    let mut response = Problem::new(StatusCode::UNAUTHORIZED)
+        .with_detail(detail)
+        .into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("Bearer error=\"invalid_token\""),
+    );
+    response
+}
+
+fn unauthorized(detail: &str) -> Response {
+    let mut response = Problem::new(StatusCode::UNAUTHORIZED)
+        .with_detail(detail)
+        .into_response();
+    response.headers_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static("ApiKey header=\"X-API-Key\""),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bearer_scheme_is_case_insensitive() {
+        assert_eq!(bearer_token("Bearer abc.def"), Some("abc.def"));
+        assert_eq!(bearer_token("bearer abc.def"), Some("abc.def"));
+        assert_eq!(bearer_token("BEARER  abc.def"), Some("abc.def"));
+        assert_eq!(bearer_token("Basic YWxhZGRpbg=="), None);
+        assert_eq!(bearer_token("Bearer"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+    }
+
+    #[test]
+    fn compares_whole_hashes() {
+        let hash = hash_secret("secret");
+        assert!(hashes_match(&hash, &hash_secret("secret")));
+        assert!(!hashes_match(&hash, &hash_secret("secreT")));
+        assert!(!hashes_match(&hash, &hash[..32]));
+    }
+}
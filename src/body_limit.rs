@@ -0,0 +1,123 @@
+use crate::problem::Problem;
+use crate::versions;
+use crate::AppState;
+use axum::body::Body;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http_body_util::Limited;
+use std::collections::HashMap;
+
+/// Request body size limits, in bytes, for every route, with optional overrides for some.
+#[derive(Debug)]
+pub struct BodyLimits {
+    pub default: usize,
+    /// Limits for routes keyed by "METHOD /route/{param}".
+    pub routes: HashMap<String, usize>,
+}
+
+impl BodyLimits {
+    fn limit_for(&self, route: &str) -> usize {
+        self.routes.get(route).copied().unwrap_or(self.default)
+    }
+}
+
+/// Parse a size in bytes, like "1048576", "512KiB" or "2MiB".
+pub fn parse_size(s: &str) -> Result<usize, SizeError> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "KiB" => 1024,
+        "MiB" => 1024 * 1024,
+        _ => return Err(SizeError),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or(SizeError)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SizeError;
+
+impl std::fmt::Display for SizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not a size like \"1048576\", \"512KiB\" or \"2MiB\"")
+    }
+}
+
+/// Parse route overrides like "POST /facility-imports=32MiB;POST /facilities=64KiB".
+pub fn parse_route_limits(s: &str) -> Result<HashMap<String, usize>, SizeError> {
+    s.split(';')
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| {
+            let (route, size) = r.rsplit_once('=').ok_or(SizeError)?;
+            Ok((route.trim().to_string(), parse_size(size)?))
+        })
+        .collect()
+}
+
+/// Middleware limiting the size of each route's request bodies, answering 413 with a problem when exceeded.
+///
+/// The limit applies to bodies after decompression, so it must run inside the decompression layer.
+/// Bodies are cut off as they are read, even when they say nothing of their length up front.
+pub async fn limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let route = format!(
+        "{} {}",
+        request.method(),
+        request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|p| versions::unversioned(p.as_str()))
+            .unwrap_or("unmatched")
+    );
+    let limit = state.body_limits.limit_for(&route);
+
+    // Compressed bodies only get bigger once decompressed, so this holds either way.
+    let declared_length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared_length.is_some_and(|length| length > limit as u64) {
+        return too_large(limit);
+    }
+
+    let request = request.map(|body| Body::new(Limited::new(body, limit)));
+    let response = next.run(request).await;
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        // Swap the extractor's plain text rejection for a problem.
+        return too_large(limit);
+    }
+    response
+}
+
+fn too_large(limit: usize) -> Response {
+    Problem::new(StatusCode::PAYLOAD_TOO_LARGE)
+        .with_detail(format!("request body is larger than {limit} bytes"))
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("1048576"), Ok(1048576));
+        assert_eq!(parse_size("512KiB"), Ok(512 * 1024));
+        assert_eq!(parse_size(" 2 MiB "), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("2MB"), Err(SizeError));
+        assert_eq!(parse_size("MiB"), Err(SizeError));
+
+        let routes =
+            parse_route_limits("POST /facility-imports=32MiB; POST /facilities=64KiB").unwrap();
+        assert_eq!(routes["POST /facilities"], 64 * 1024);
+    }
+}
@@ -0,0 +1,341 @@
+use crate::core;
+use crate::metrics;
+use crate::replica::ReadFrom;
+use crate::settings::CacheSettings;
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use sha2::{Digest, Sha256};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+/// Bounded map whose entries expire after a while, evicting the least recently used when full.
+pub struct Cache<K, V> {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries<K, V>>,
+}
+
+struct Entries<K, V> {
+    values: HashMap<K, Entry<V>>,
+    /// Keys by when they were last used, oldest first.
+    uses: BTreeMap<u64, K>,
+    next_use: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    expires: Instant,
+    used: u64,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Cache<K, V> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Cache {
+            capacity,
+            ttl,
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                uses: BTreeMap::new(),
+                next_use: 0,
+            }),
+        }
+    }
+
+    pub fn get<Q>(&self, key: &Q, now: Instant) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let Entries {
+            values,
+            uses,
+            next_use,
+        } = &mut *entries;
+        let entry = values.get_mut(key)?;
+        if entry.expires <= now {
+            uses.remove(&entry.used);
+            values.remove(key);
+            return None;
+        }
+        let key = uses.remove(&entry.used)?;
+        entry.used = *next_use;
+        uses.insert(*next_use, key);
+        *next_use += 1;
+        Some(entry.value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V, now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        self.replace(&mut entries, key, value, now + self.ttl);
+    }
+
+    /// Replace the value for a key with one made from the current value, if it hasn't expired, under one lock.
+    ///
+    /// `make` also says when the new value expires, in place of the TTL, and what to hand back to the caller.
+    pub fn update<R>(
+        &self,
+        key: K,
+        now: Instant,
+        make: impl FnOnce(Option<V>) -> (V, Instant, R),
+    ) -> R {
+        let mut entries = self.entries.lock().unwrap();
+        let current = entries.values.remove(&key).and_then(|old| {
+            entries.uses.remove(&old.used);
+            (old.expires > now).then_some(old.value)
+        });
+        let (value, expires, result) = make(current);
+        self.replace(&mut entries, key, value, expires);
+        result
+    }
+
+    fn replace(&self, entries: &mut Entries<K, V>, key: K, value: V, expires: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(old) = entries.values.remove(&key) {
+            entries.uses.remove(&old.used);
+        }
+        while entries.values.len() >= self.capacity {
+            let Some((_, oldest)) = entries.uses.pop_first() else {
+                break;
+            };
+            entries.values.remove(&oldest);
+        }
+        let used = entries.next_use;
+        entries.next_use += 1;
+        entries.uses.insert(used, key.clone());
+        entries.values.insert(
+            key,
+            Entry {
+                value,
+                expires,
+                used,
+            },
+        );
+    }
+
+    pub fn remove<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.values.remove(key) {
+            entries.uses.remove(&old.used);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.values.clear();
+        entries.uses.clear();
+    }
+}
+
+/// Facilities by UID, and lists of them by filter, as recently read from storage.
+///
+/// Writes through this instance invalidate what they touch. Writes through other instances or the command
+/// line show up once entries expire.
+pub struct FacilityCache {
+    facilities: Cache<String, core::Facility>,
+    lists: Cache<String, Vec<core::Facility>>,
+    /// How long after a write reads may still miss it, like while a replica catches up.
+    settle: Duration,
+    last_write: Mutex<Option<Instant>>,
+}
+
+impl FacilityCache {
+    pub fn new(settings: &CacheSettings, settle: Duration) -> Self {
+        FacilityCache {
+            facilities: Cache::new(settings.max_facilities, settings.ttl),
+            lists: Cache::new(settings.max_lists, settings.ttl),
+            settle,
+            last_write: Mutex::new(None),
+        }
+    }
+
+    /// The cached facility with this UID, unless the client needs to see its own recent writes.
+    pub fn facility(&self, uid: &str, read_from: ReadFrom) -> Option<core::Facility> {
+        if read_from == ReadFrom::Primary {
+            return None;
+        }
+        let facility = self.facilities.get(uid, Instant::now());
+        metrics::count_cache_lookup("facility", facility.is_some());
+        facility
+    }
+
+    /// The cached list for this filter key, unless the client needs to see its own recent writes.
+    pub fn list(&self, key: &str, read_from: ReadFrom) -> Option<Vec<core::Facility>> {
+        if read_from == ReadFrom::Primary {
+            return None;
+        }
+        let list = self.lists.get(key, Instant::now());
+        metrics::count_cache_lookup("list", list.is_some());
+        list
+    }
+
+    /// Cache a facility read from storage, unless a write may have happened since the read began.
+    pub fn insert_facility(&self, facility: core::Facility, read_started: Instant) {
+        if self.may_be_stale(read_started) {
+            return;
+        }
+        self.facilities
+            .insert(facility.uid.clone(), facility, Instant::now());
+    }
+
+    /// Cache a list read from storage, unless a write may have happened since the read began.
+    pub fn insert_list(&self, key: String, list: Vec<core::Facility>, read_started: Instant) {
+        if self.may_be_stale(read_started) {
+            return;
+        }
+        self.lists.insert(key, list, Instant::now());
+    }
+
+    /// Forget the facilities with these UIDs, and every list, as any of them might include the facilities.
+    pub fn invalidate<'a>(&self, uids: impl IntoIterator<Item = &'a str>) {
+        *self.last_write.lock().unwrap() = Some(Instant::now());
+        for uid in uids {
+            self.facilities.remove(uid);
+        }
+        self.lists.clear();
+    }
+
+    fn may_be_stale(&self, read_started: Instant) -> bool {
+        self.last_write
+            .lock()
+            .unwrap()
+            .is_some_and(|last_write| last_write + self.settle >= read_started)
+    }
+}
+
+/// Middleware making clients revalidate successful reads before reusing them, as any copy they keep could miss
+/// their own writes or others'. Reads carry an ETag, and a matching `If-None-Match` gets `304 Not Modified`.
+pub async fn cache_control(request: Request, next: Next) -> Response {
+    let is_read = request.method() == Method::GET || request.method() == Method::HEAD;
+    let if_none_match = request.headers().get(header::IF_NONE_MATCH).cloned();
+    let response = next.run(request).await;
+    if !is_read || !response.status().is_success() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, usize::MAX).await else {
+        error!("error reading response body to tag it");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    // Weak, as compression further out changes the bytes but not what they mean.
+    let etag = format!("W/\"{}\"", &hex::encode(Sha256::digest(&bytes))[..32]);
+    parts.headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-cache"),
+    );
+    if let Ok(value) = HeaderValue::try_from(&etag) {
+        parts.headers.insert(header::ETAG, value);
+    }
+
+    if if_none_match.is_some_and(|v| matches_etag(&v, &etag)) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+/// Whether an `If-None-Match` header lists an ETag, comparing them weakly.
+fn matches_etag(if_none_match: &HeaderValue, etag: &str) -> bool {
+    let Ok(if_none_match) = if_none_match.to_str() else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_and_expired() {
+        let cache = Cache::new(2, Duration::from_secs(10));
+        let start = Instant::now();
+        cache.insert("a", 1, start);
+        cache.insert("b", 2, start);
+        assert_eq!(cache.get(&"a", start), Some(1));
+        // "b" is now the least recently used.
+        cache.insert("c", 3, start);
+        assert_eq!(cache.get(&"b", start), None);
+        assert_eq!(cache.get(&"a", start), Some(1));
+        assert_eq!(cache.get(&"c", start + Duration::from_secs(10)), None);
+        assert_eq!(cache.get(&"a", start + Duration::from_secs(9)), Some(1));
+    }
+
+    #[test]
+    fn skips_reads_that_may_have_missed_a_write() {
+        let settings = CacheSettings {
+            ttl: Duration::from_secs(30),
+            max_facilities: 10,
+            max_lists: 10,
+        };
+        let cache = FacilityCache::new(&settings, Duration::ZERO);
+        let read_started = Instant::now();
+        cache.invalidate([]);
+        cache.insert_list(String::from("all"), Vec::new(), read_started);
+        assert_eq!(cache.list("all", ReadFrom::Replica), None);
+
+        let later = Instant::now() + Duration::from_secs(1);
+        cache.insert_list(String::from("all"), Vec::new(), later);
+        assert_eq!(cache.list("all", ReadFrom::Replica), Some(Vec::new()));
+        assert_eq!(cache.list("all", ReadFrom::Primary), None);
+    }
+
+    #[tokio::test]
+    async fn revalidates_reads_with_etags() {
+        use axum::routing::get;
+        use axum::Router;
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/", get(|| async { "facilities" }))
+            .layer(axum::middleware::from_fn(cache_control));
+        let get = |if_none_match: Option<&str>| {
+            let mut request = Request::get("/");
+            if let Some(tags) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, tags);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = get(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CACHE_CONTROL],
+            "private, no-cache"
+        );
+        let etag = response.headers()[header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let response = get(Some(&format!(
+            "\"other\", {}",
+            etag.trim_start_matches("W/")
+        )))
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag.as_str());
+        let response = get(Some("\"other\"")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
@@ -1,15 +1,17 @@
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveDateTime};
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::str::FromStr;
+use utoipa::ToSchema;
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
-pub struct Latitude(f32);
+pub struct Latitude(f64);
 
-impl TryFrom<f32> for Latitude {
+impl TryFrom<f64> for Latitude {
     type Error = LatitudeBoundsError;
 
-    fn try_from(value: f32) -> Result<Self, Self::Error> {
-        if value < -90.0 || value > 90.0 {
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if !(-90.0..=90.0).contains(&value) {
             Err(LatitudeBoundsError)
         } else {
             Ok(Self(value))
@@ -17,9 +19,16 @@ impl TryFrom<f32> for Latitude {
     }
 }
 
-impl From<Latitude> for f32 {
+impl Latitude {
+    /// Round to a number of decimal places.
+    pub fn round(&self, decimals: u32) -> Self {
+        Self(round_to(self.0, decimals))
+    }
+}
+
+impl From<Latitude> for f64 {
     fn from(value: Latitude) -> Self {
-        f32::from(value.0)
+        value.0
     }
 }
 
@@ -37,19 +46,19 @@ impl<'de> Deserialize<'de> for Latitude {
     where
         D: Deserializer<'de>,
     {
-        let value: f32 = Deserialize::deserialize(deserializer)?;
+        let value: f64 = Deserialize::deserialize(deserializer)?;
         Self::try_from(value).map_err(D::Error::custom)
     }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize)]
-pub struct Longitude(f32);
+pub struct Longitude(f64);
 
-impl TryFrom<f32> for Longitude {
+impl TryFrom<f64> for Longitude {
     type Error = LongitudeBoundsError;
 
-    fn try_from(value: f32) -> Result<Self, Self::Error> {
-        if value < -180.0 || value > 180.0 {
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if !(-180.0..=180.0).contains(&value) {
             Err(LongitudeBoundsError)
         } else {
             Ok(Self(value))
@@ -57,9 +66,16 @@ impl TryFrom<f32> for Longitude {
     }
 }
 
-impl From<Longitude> for f32 {
+impl Longitude {
+    /// Round to a number of decimal places.
+    pub fn round(&self, decimals: u32) -> Self {
+        Self(round_to(self.0, decimals))
+    }
+}
+
+impl From<Longitude> for f64 {
     fn from(value: Longitude) -> Self {
-        f32::from(value.0)
+        value.0
     }
 }
 
@@ -77,31 +93,58 @@ impl<'de> Deserialize<'de> for Longitude {
     where
         D: Deserializer<'de>,
     {
-        let value: f32 = Deserialize::deserialize(deserializer)?;
+        let value: f64 = Deserialize::deserialize(deserializer)?;
         Self::try_from(value).map_err(D::Error::custom)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// Most decimal places a coordinate can meaningfully be rounded to.
+///
+/// f64 holds ~15 significant digits and coordinates use up to 3 of them before the decimal point.
+pub const MAX_COORDINATE_PRECISION: u32 = 12;
+
+fn round_to(value: f64, decimals: u32) -> f64 {
+    let factor = 10_f64.powi(decimals.min(MAX_COORDINATE_PRECISION) as i32);
+    (value * factor).round() / factor
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, ToSchema)]
+#[schema(example = json!({
+    "uid": "the_uid",
+    "company": "some company",
+    "segment": "some segment",
+    "technology": "some tech",
+    "latitude": 35.6,
+    "longitude": -88.8,
+    "announcement_date": "2024-12-25",
+    "estimated_investment": 1000
+}))]
 pub struct Facility {
     pub uid: String,
     pub company: String,
     pub segment: String,
     pub technology: String,
+    /// Degrees north of the equator.
+    #[schema(value_type = f64, minimum = -90.0, maximum = 90.0)]
     pub latitude: Latitude,
+    /// Degrees east of the prime meridian.
+    #[schema(value_type = f64, minimum = -180.0, maximum = 180.0)]
     pub longitude: Longitude,
+    /// Date as "YYYY-MM-DD".
     pub announcement_date: NaiveDate,
+    /// Estimated investment in US dollars, if announced.
     pub estimated_investment: Option<i64>,
 }
 
 impl Facility {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         uid: String,
         company: String,
         segment: String,
         technology: String,
-        latitude: f32,
-        longitude: f32,
+        latitude: f64,
+        longitude: f64,
         announcement_date: NaiveDate,
         estimated_investment: Option<i64>,
     ) -> Result<Self, FacilityError> {
@@ -126,6 +169,15 @@ impl Facility {
             estimated_investment,
         })
     }
+
+    /// Round latitude and longitude to a number of decimal places, e.g. before sending to clients.
+    pub fn with_coordinate_precision(self, decimals: u32) -> Self {
+        Facility {
+            latitude: self.latitude.round(decimals),
+            longitude: self.longitude.round(decimals),
+            ..self
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -134,6 +186,352 @@ pub enum FacilityError {
     LongitudeBounds,
 }
 
+impl std::fmt::Display for FacilityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FacilityError::LatitudeBounds => write!(f, "latitude {LatitudeBoundsError}"),
+            FacilityError::LongitudeBounds => write!(f, "longitude {LongitudeBoundsError}"),
+        }
+    }
+}
+
+impl std::error::Error for FacilityError {}
+
+/// Area within a distance of a point, e.g. "35.6,-88.8,5000" for 5 km around (35.6, -88.8).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Circle {
+    pub latitude: Latitude,
+    pub longitude: Longitude,
+    pub radius_meters: f64,
+}
+
+impl FromStr for Circle {
+    type Err = ShapeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [latitude, longitude, radius_meters] = parse_numbers(s)?;
+        if radius_meters.is_nan() || radius_meters < 0.0 {
+            return Err(ShapeError::Radius);
+        }
+        Ok(Circle {
+            latitude: Latitude::try_from(latitude).map_err(|_| ShapeError::LatitudeBounds)?,
+            longitude: Longitude::try_from(longitude).map_err(|_| ShapeError::LongitudeBounds)?,
+            radius_meters,
+        })
+    }
+}
+
+/// Area between two meridians and two parallels, as "west,south,east,north" in degrees.
+///
+/// A box whose west edge is greater than its east edge crosses the antimeridian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub west: Longitude,
+    pub south: Latitude,
+    pub east: Longitude,
+    pub north: Latitude,
+}
+
+impl BoundingBox {
+    /// Whether the box wraps around the antimeridian.
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.west.0 > self.east.0
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = ShapeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let [west, south, east, north] = parse_numbers(s)?;
+        let south = Latitude::try_from(south).map_err(|_| ShapeError::LatitudeBounds)?;
+        let north = Latitude::try_from(north).map_err(|_| ShapeError::LatitudeBounds)?;
+        if south.0 > north.0 {
+            return Err(ShapeError::LatitudeOrder);
+        }
+        Ok(BoundingBox {
+            west: Longitude::try_from(west).map_err(|_| ShapeError::LongitudeBounds)?,
+            south,
+            east: Longitude::try_from(east).map_err(|_| ShapeError::LongitudeBounds)?,
+            north,
+        })
+    }
+}
+
+/// Parse exactly N comma-separated numbers.
+fn parse_numbers<const N: usize>(s: &str) -> Result<[f64; N], ShapeError> {
+    let numbers = s
+        .split(',')
+        .map(|x| x.trim().parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| ShapeError::Format)?;
+    numbers.try_into().map_err(|_| ShapeError::Format)
+}
+
+/// Area made of one or more polygons, read from a GeoJSON Polygon or MultiPolygon geometry.
+///
+/// Polygons may have holes and may cross the antimeridian.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(try_from = "GeoJsonGeometry")]
+pub struct Region {
+    /// Rings of (longitude, latitude) for each polygon, the first ring being the exterior.
+    ///
+    /// Longitudes are unwrapped so that no edge spans more than 180 degrees, so they may fall outside [-180, 180].
+    polygons: Vec<Vec<Vec<(f64, f64)>>>,
+}
+
+impl Region {
+    /// Whether a point falls inside the region and outside any of its holes.
+    pub fn contains(&self, latitude: &Latitude, longitude: &Longitude) -> bool {
+        let (y, x) = (latitude.0, longitude.0);
+        // Unwrapped polygons can reach past the antimeridian, so also try the point one turn either way.
+        self.polygons.iter().any(|rings| {
+            [x - 360.0, x, x + 360.0]
+                .into_iter()
+                .any(|x| polygon_contains(rings, x, y))
+        })
+    }
+
+    /// Smallest box around the whole region.
+    pub fn bounding_box(&self) -> BoundingBox {
+        let vertices = self.polygons.iter().flatten().flatten();
+        let (mut west, mut south, mut east, mut north) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        for &(x, y) in vertices {
+            west = west.min(x);
+            east = east.max(x);
+            south = south.min(y);
+            north = north.max(y);
+        }
+        if east - west >= 360.0 {
+            (west, east) = (-180.0, 180.0);
+        } else {
+            (west, east) = (wrap_longitude(west), wrap_longitude(east));
+        }
+        BoundingBox {
+            west: Longitude(west),
+            south: Latitude(south),
+            east: Longitude(east),
+            north: Latitude(north),
+        }
+    }
+
+    /// Each polygon as a GeoJSON Polygon with planar edges, for storage to test points against.
+    ///
+    /// Parts of a polygon reaching past the antimeridian get a copy one turn east or west, so points
+    /// in [-180, 180] fall inside one of them exactly when `contains` says so.
+    pub fn geojson_polygons(&self) -> Vec<String> {
+        let mut geojson = Vec::new();
+        for rings in &self.polygons {
+            let (west, east) = rings
+                .iter()
+                .flatten()
+                .fold((f64::MAX, f64::MIN), |(w, e), &(x, _)| (w.min(x), e.max(x)));
+            let turns =
+                ((-180.0 - east) / 360.0).ceil() as i32..=((180.0 - west) / 360.0).floor() as i32;
+            for turn in turns {
+                let shift = 360.0 * f64::from(turn);
+                let coordinates: Vec<Vec<[f64; 2]>> = rings
+                    .iter()
+                    .map(|ring| ring.iter().map(|&(x, y)| [x + shift, y]).collect())
+                    .collect();
+                geojson.push(
+                    serde_json::json!({"type": "Polygon", "coordinates": coordinates}).to_string(),
+                );
+            }
+        }
+        geojson
+    }
+}
+
+/// Bring a longitude back into [-180, 180].
+fn wrap_longitude(x: f64) -> f64 {
+    if (-180.0..=180.0).contains(&x) {
+        x
+    } else {
+        (x + 180.0).rem_euclid(360.0) - 180.0
+    }
+}
+
+/// Point in polygon test with the even-odd rule, so points inside holes are outside the polygon.
+fn polygon_contains(rings: &[Vec<(f64, f64)>], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    for ring in rings {
+        for (a, b) in ring.iter().zip(ring.iter().skip(1)) {
+            if (a.1 > y) != (b.1 > y) && x < (b.0 - a.0) * (y - a.1) / (b.1 - a.1) + a.0 {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// GeoJSON geometries that can describe a Region.
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "coordinates")]
+enum GeoJsonGeometry {
+    Polygon(Vec<Vec<Vec<f64>>>),
+    MultiPolygon(Vec<Vec<Vec<Vec<f64>>>>),
+}
+
+impl TryFrom<GeoJsonGeometry> for Region {
+    type Error = RegionError;
+
+    fn try_from(value: GeoJsonGeometry) -> Result<Self, Self::Error> {
+        let polygons = match value {
+            GeoJsonGeometry::Polygon(p) => vec![p],
+            GeoJsonGeometry::MultiPolygon(m) => m,
+        };
+        if polygons.is_empty() {
+            return Err(RegionError::Empty);
+        }
+        let polygons = polygons
+            .into_iter()
+            .map(unwrap_polygon)
+            .collect::<Result<_, _>>()?;
+        Ok(Region { polygons })
+    }
+}
+
+/// Validate a GeoJSON polygon and unwrap its longitudes across the antimeridian.
+fn unwrap_polygon(rings: Vec<Vec<Vec<f64>>>) -> Result<Vec<Vec<(f64, f64)>>, RegionError> {
+    if rings.is_empty() {
+        return Err(RegionError::Empty);
+    }
+    // Holes are unwrapped next to the exterior's first vertex so they line up with it.
+    let mut reference: Option<f64> = None;
+    rings
+        .into_iter()
+        .map(|ring| {
+            if ring.len() < 4 || ring.first() != ring.last() {
+                return Err(RegionError::Ring);
+            }
+            let mut previous = reference;
+            let mut unwrapped = Vec::with_capacity(ring.len());
+            for position in ring {
+                let (x, y) = match position[..] {
+                    [x, y, ..] => (x, y),
+                    _ => return Err(RegionError::Position),
+                };
+                let x = Longitude::try_from(x)
+                    .map_err(|_| RegionError::LongitudeBounds)?
+                    .0;
+                let y = Latitude::try_from(y)
+                    .map_err(|_| RegionError::LatitudeBounds)?
+                    .0;
+                let x = match previous {
+                    Some(p) => x + 360.0 * ((p - x) / 360.0).round(),
+                    None => x,
+                };
+                previous = Some(x);
+                reference.get_or_insert(x);
+                unwrapped.push((x, y));
+            }
+            Ok(unwrapped)
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegionError {
+    Empty,
+    Ring,
+    Position,
+    LatitudeBounds,
+    LongitudeBounds,
+}
+
+impl std::fmt::Display for RegionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RegionError::Empty => write!(f, "region has no polygons or rings"),
+            RegionError::Ring => write!(f, "ring is not closed or has fewer than 4 positions"),
+            RegionError::Position => write!(f, "position has fewer than 2 numbers"),
+            RegionError::LatitudeBounds => write!(f, "latitude {LatitudeBoundsError}"),
+            RegionError::LongitudeBounds => write!(f, "longitude {LongitudeBoundsError}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShapeError {
+    Format,
+    LatitudeBounds,
+    LongitudeBounds,
+    LatitudeOrder,
+    Radius,
+}
+
+impl std::fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShapeError::Format => write!(f, "wrong number of comma-separated numbers"),
+            ShapeError::LatitudeBounds => write!(f, "latitude {LatitudeBoundsError}"),
+            ShapeError::LongitudeBounds => write!(f, "longitude {LongitudeBoundsError}"),
+            ShapeError::LatitudeOrder => write!(f, "south is north of north"),
+            ShapeError::Radius => write!(f, "radius is negative"),
+        }
+    }
+}
+
+/// What an API client is allowed to do. Each scope includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+    Admin,
+}
+
+impl Scope {
+    /// Whether this scope covers everything `required` does.
+    pub fn allows(self, required: Scope) -> bool {
+        self >= required
+    }
+}
+
+impl FromStr for Scope {
+    type Err = ScopeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "write" => Ok(Scope::Write),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(ScopeError),
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScopeError;
+
+impl std::fmt::Display for ScopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not one of read, write or admin")
+    }
+}
+
+impl std::error::Error for ScopeError {}
+
+/// A client's API key. Only a hash of its secret is ever stored.
+#[derive(Clone, Debug, PartialEq, Serialize, ToSchema)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: Scope,
+    pub created_at: NaiveDateTime,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -169,6 +567,138 @@ mod tests {
         assert_eq!(facility_result, Err(FacilityError::LongitudeBounds));
     }
 
+    #[test]
+    fn coordinates_keep_double_precision() {
+        let json_facility = json!({
+            "uid": "a_uid",
+            "company": "fancy company",
+            "segment": "some sector",
+            "technology": "fancy tech",
+            "latitude": 35.606123456789,
+            "longitude": -88.830987654321,
+            "announcement_date": "2024-12-24",
+            "estimated_investment": null
+        });
+        let facility: Facility = serde_json::from_value(json_facility.clone()).unwrap();
+        assert_eq!(serde_json::to_value(facility).unwrap(), json_facility);
+    }
+
+    #[test]
+    fn round_coordinate_precision() {
+        let facility = Facility::new(
+            String::from("a_uid"),
+            String::from("fancy company"),
+            String::from("some sector"),
+            String::from("fancy tech"),
+            35.606123456789,
+            -88.830987654321,
+            NaiveDate::from_ymd_opt(2024, 12, 24).unwrap(),
+            None,
+        )
+        .unwrap()
+        .with_coordinate_precision(3);
+        assert_eq!(facility.latitude, Latitude::try_from(35.606).unwrap());
+        assert_eq!(facility.longitude, Longitude::try_from(-88.831).unwrap());
+    }
+
+    #[test]
+    fn parse_bounding_box() {
+        let bbox: BoundingBox = "170.0, -10.0, -170.0, 10.0".parse().unwrap();
+        assert!(bbox.crosses_antimeridian());
+        assert_eq!(bbox.south, Latitude::try_from(-10.0).unwrap());
+        assert_eq!("-80,10,-70".parse::<BoundingBox>(), Err(ShapeError::Format));
+        assert_eq!(
+            "-80,10,-70,5".parse::<BoundingBox>(),
+            Err(ShapeError::LatitudeOrder)
+        );
+    }
+
+    #[test]
+    fn parse_circle() {
+        let circle: Circle = "35.6,-88.8,5000".parse().unwrap();
+        assert_eq!(circle.radius_meters, 5000.0);
+        assert_eq!(
+            "95,-88.8,5000".parse::<Circle>(),
+            Err(ShapeError::LatitudeBounds)
+        );
+        assert_eq!("35.6,-88.8,-1".parse::<Circle>(), Err(ShapeError::Radius));
+    }
+
+    fn point(latitude: f64, longitude: f64) -> (Latitude, Longitude) {
+        (
+            Latitude::try_from(latitude).unwrap(),
+            Longitude::try_from(longitude).unwrap(),
+        )
+    }
+
+    #[test]
+    fn region_with_hole() {
+        let region: Region = serde_json::from_value(json!({
+            "type": "Polygon",
+            "coordinates": [
+                [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0], [0.0, 0.0]],
+                [[4.0, 4.0], [6.0, 4.0], [6.0, 6.0], [4.0, 6.0], [4.0, 4.0]]
+            ]
+        }))
+        .unwrap();
+        let (lat, lon) = point(2.0, 2.0);
+        assert!(region.contains(&lat, &lon));
+        let (lat, lon) = point(5.0, 5.0);
+        assert!(!region.contains(&lat, &lon));
+        let (lat, lon) = point(5.0, 15.0);
+        assert!(!region.contains(&lat, &lon));
+    }
+
+    #[test]
+    fn region_across_antimeridian() {
+        let region: Region = serde_json::from_value(json!({
+            "type": "MultiPolygon",
+            "coordinates": [[
+                [[170.0, -10.0], [-170.0, -10.0], [-170.0, 10.0], [170.0, 10.0], [170.0, -10.0]]
+            ]]
+        }))
+        .unwrap();
+        let (lat, lon) = point(0.0, 179.0);
+        assert!(region.contains(&lat, &lon));
+        let (lat, lon) = point(0.0, -175.0);
+        assert!(region.contains(&lat, &lon));
+        let (lat, lon) = point(0.0, 0.0);
+        assert!(!region.contains(&lat, &lon));
+
+        let bbox = region.bounding_box();
+        assert!(bbox.crosses_antimeridian());
+        assert_eq!(bbox.west, Longitude::try_from(170.0).unwrap());
+        assert_eq!(bbox.east, Longitude::try_from(-170.0).unwrap());
+
+        // Unwrapped to 170..190, so storage also gets the copy at -190..-170.
+        let polygons: Vec<serde_json::Value> = region
+            .geojson_polygons()
+            .iter()
+            .map(|p| serde_json::from_str(p).unwrap())
+            .collect();
+        assert_eq!(polygons.len(), 2);
+        assert_eq!(polygons[0]["coordinates"][0][1], json!([-170.0, -10.0]));
+        assert_eq!(polygons[1]["coordinates"][0][1], json!([190.0, -10.0]));
+    }
+
+    #[test]
+    fn region_rejects_open_ring() {
+        let result: Result<Region, _> = serde_json::from_value(json!({
+            "type": "Polygon",
+            "coordinates": [[[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]]]
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn scopes_include_lower_scopes() {
+        assert!(Scope::Admin.allows(Scope::Read));
+        assert!(Scope::Write.allows(Scope::Write));
+        assert!(!Scope::Read.allows(Scope::Write));
+        assert_eq!("admin".parse::<Scope>(), Ok(Scope::Admin));
+        assert_eq!("root".parse::<Scope>(), Err(ScopeError));
+    }
+
     #[test]
     fn deserialize_json_facility_with_investment() {
         let expected = Facility {
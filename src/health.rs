@@ -0,0 +1,178 @@
+use crate::migrations;
+use crate::storage;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::error;
+use utoipa::ToSchema;
+
+/// How long readiness checks may take before the service counts as not ready.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Health of the service, or one of its dependencies.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Health {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database: Option<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub migrations: Option<Check>,
+    /// The read replica, when there is one. Reads fall back to the primary without it, so it doesn't affect readiness.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replica: Option<Check>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Check {
+            ok: true,
+            detail: None,
+        }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        Check {
+            ok: false,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+/// Handle liveness checks. The process answering is all there is to check.
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    responses((status = 200, description = "The service is running", body = Health)),
+)]
+pub async fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        database: None,
+        migrations: None,
+        replica: None,
+    })
+}
+
+/// Handle readiness checks, reporting whether the database is reachable and fully migrated.
+///
+/// Once shutdown starts the service reports itself unavailable, so load balancers stop sending it work.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    responses(
+        (status = 200, description = "The service can take requests", body = Health),
+        (status = 503, description = "The database is unavailable or not fully migrated, or the service is shutting down", body = Health),
+    ),
+)]
+pub async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Health>) {
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Health {
+                status: "shutting down",
+                database: None,
+                migrations: None,
+                replica: None,
+            }),
+        );
+    }
+
+    let no_answer = || {
+        Check::failed(format!(
+            "no answer within {} seconds",
+            READINESS_TIMEOUT.as_secs()
+        ))
+    };
+    let (database_checks, replica) = tokio::join!(
+        tokio::time::timeout(READINESS_TIMEOUT, check_database(&state)),
+        tokio::time::timeout(READINESS_TIMEOUT, check_replica(&state)),
+    );
+    let (database, migrations) =
+        database_checks.unwrap_or_else(|_| (no_answer(), Check::failed("database unavailable")));
+    let replica = replica.unwrap_or_else(|_| Some(no_answer()));
+
+    let ready = database.ok && migrations.ok;
+    (
+        if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        },
+        Json(Health {
+            status: if ready { "ready" } else { "unavailable" },
+            database: Some(database),
+            migrations: Some(migrations),
+            replica,
+        }),
+    )
+}
+
+async fn check_database(state: &AppState) -> (Check, Check) {
+    let client = match state.conn_pool.get().await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("readiness check unable to collect client from connection pool {e:?}");
+            return (
+                Check::failed(e.to_string()),
+                Check::failed("database unavailable"),
+            );
+        }
+    };
+    let interaction_result = client
+        .interact(|conn| {
+            storage::ping(conn)?;
+            Ok::<_, migrations::MigrationError>(migrations::pending_migrations(conn))
+        })
+        .await;
+    match interaction_result {
+        Ok(Ok(Ok(pending))) if pending.is_empty() => (Check::ok(), Check::ok()),
+        Ok(Ok(Ok(pending))) => (
+            Check::ok(),
+            Check::failed(format!("pending {}", pending.join(", "))),
+        ),
+        Ok(Ok(Err(e))) => (Check::ok(), Check::failed(e.to_string())),
+        Ok(Err(e)) => (
+            Check::failed(e.to_string()),
+            Check::failed("database unavailable"),
+        ),
+        Err(e) => {
+            error!("readiness check error interacting through connection pool {e:?}");
+            (
+                Check::failed("error interacting through connection pool"),
+                Check::failed("database unavailable"),
+            )
+        }
+    }
+}
+
+async fn check_replica(state: &AppState) -> Option<Check> {
+    let replica = state.replica.as_ref()?;
+    let client = match replica.pool.get().await {
+        Ok(c) => c,
+        Err(e) => {
+            error!("readiness check unable to collect client from replica pool {e:?}");
+            return Some(Check::failed(e.to_string()));
+        }
+    };
+    Some(match client.interact(storage::ping).await {
+        Ok(Ok(())) => Check::ok(),
+        Ok(Err(e)) => Check::failed(e.to_string()),
+        Err(e) => {
+            error!("readiness check error interacting through replica pool {e:?}");
+            Check::failed("error interacting through connection pool")
+        }
+    })
+}
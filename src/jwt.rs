@@ -0,0 +1,295 @@
+use crate::auth::Principal;
+use crate::core::Scope;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+/// Where keys for checking token signatures come from.
+pub enum KeySource {
+    /// A single key, e.g. a shared HMAC secret or a PEM public key.
+    Static(DecodingKey, Algorithm),
+    /// A JSON Web Key Set from a file path or http(s) URL, picked from by each token's "kid".
+    Jwks {
+        location: String,
+        keys: RwLock<JwkSet>,
+    },
+}
+
+/// Checks bearer tokens issued by other services and maps their claims to a Principal.
+pub struct JwtValidator {
+    pub issuer: String,
+    pub audience: String,
+    /// Claim holding the token's roles, either as an array or a space-separated string.
+    pub roles_claim: String,
+    pub keys: KeySource,
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+impl JwtValidator {
+    /// Check a token's signature, exp, nbf, issuer and audience, returning who it identifies.
+    ///
+    /// The Principal gets the highest scope among the token's roles that name one.
+    pub fn validate(&self, token: &str) -> Result<Principal, JwtError> {
+        let header = decode_header(token).map_err(JwtError::Token)?;
+        let (key, algorithm) = match &self.keys {
+            KeySource::Static(key, algorithm) => (key.clone(), *algorithm),
+            KeySource::Jwks { keys, .. } => {
+                let kid = header.kid.ok_or(JwtError::UnknownKey)?;
+                let keys = keys.read().unwrap();
+                let jwk = keys.find(&kid).ok_or(JwtError::UnknownKey)?;
+                let algorithm = match jwk.common.key_algorithm {
+                    Some(a) => a.to_string().parse().map_err(JwtError::Token)?,
+                    None => header.alg,
+                };
+                (
+                    DecodingKey::from_jwk(jwk).map_err(JwtError::Token)?,
+                    algorithm,
+                )
+            }
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "sub", "iss", "aud"]);
+        validation.validate_nbf = true;
+
+        let claims = decode::<Claims>(token, &key, &validation)
+            .map_err(JwtError::Token)?
+            .claims;
+        let roles: Vec<&str> = match claims.other.get(&self.roles_claim) {
+            Some(serde_json::Value::String(s)) => s.split_whitespace().collect(),
+            Some(serde_json::Value::Array(a)) => a.iter().filter_map(|r| r.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        let scope = roles
+            .into_iter()
+            .filter_map(|r| r.parse::<Scope>().ok())
+            .max()
+            .ok_or(JwtError::NoRole)?;
+
+        Ok(Principal {
+            subject: claims.sub,
+            scope,
+        })
+    }
+
+    /// Reload the JSON Web Key Set, if keys come from one.
+    pub async fn refresh_jwks(&self) -> Result<(), JwtError> {
+        if let KeySource::Jwks { location, keys } = &self.keys {
+            let jwks = load_jwks(location).await?;
+            *keys.write().unwrap() = jwks;
+        }
+        Ok(())
+    }
+}
+
+/// Read a JSON Web Key Set from a file path or http(s) URL.
+pub async fn load_jwks(location: &str) -> Result<JwkSet, JwtError> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let response = reqwest::get(location)
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| JwtError::Jwks(e.to_string()))?;
+        response
+            .json()
+            .await
+            .map_err(|e| JwtError::Jwks(e.to_string()))
+    } else {
+        let contents = tokio::fs::read_to_string(location)
+            .await
+            .map_err(|e| JwtError::Jwks(e.to_string()))?;
+        serde_json::from_str(&contents).map_err(|e| JwtError::Jwks(e.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum JwtError {
+    /// Token is malformed, has a bad signature or fails a claim check.
+    Token(jsonwebtoken::errors::Error),
+    /// Token was signed with a key we don't know.
+    UnknownKey,
+    /// Token has no role that maps to a scope.
+    NoRole,
+    /// JSON Web Key Set couldn't be loaded.
+    Jwks(String),
+}
+
+impl std::fmt::Display for JwtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JwtError::Token(e) => write!(f, "invalid token: {e}"),
+            JwtError::UnknownKey => write!(f, "token signed with unknown key"),
+            JwtError::NoRole => write!(f, "token has no read, write or admin role"),
+            JwtError::Jwks(e) => write!(f, "unable to load JSON Web Key Set: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    const SECRET: &[u8] = b"a very secret secret";
+
+    fn validator(keys: KeySource) -> JwtValidator {
+        JwtValidator {
+            issuer: String::from("https://issuer.example"),
+            audience: String::from("afasttoywebapi"),
+            roles_claim: String::from("roles"),
+            keys,
+        }
+    }
+
+    fn token(header: Header, claims: serde_json::Value) -> String {
+        encode(&header, &claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn claims() -> serde_json::Value {
+        json!({
+            "sub": "some-service",
+            "iss": "https://issuer.example",
+            "aud": "afasttoywebapi",
+            "exp": jsonwebtoken::get_current_timestamp() + 600,
+            "roles": ["read", "write", "something-else"]
+        })
+    }
+
+    #[test]
+    fn valid_token_with_static_key() {
+        let v = validator(KeySource::Static(
+            DecodingKey::from_secret(SECRET),
+            Algorithm::HS256,
+        ));
+        let principal = v.validate(&token(Header::default(), claims())).unwrap();
+        assert_eq!(principal.subject, "some-service");
+        assert_eq!(principal.scope, Scope::Write);
+    }
+
+    #[test]
+    fn rejects_wrong_issuer_expired_and_roleless_tokens() {
+        let v = validator(KeySource::Static(
+            DecodingKey::from_secret(SECRET),
+            Algorithm::HS256,
+        ));
+
+        let mut wrong_issuer = claims();
+        wrong_issuer["iss"] = json!("https://someone.else");
+        assert!(v.validate(&token(Header::default(), wrong_issuer)).is_err());
+
+        let mut expired = claims();
+        expired["exp"] = json!(jsonwebtoken::get_current_timestamp() - 600);
+        assert!(v.validate(&token(Header::default(), expired)).is_err());
+
+        let mut not_yet_valid = claims();
+        not_yet_valid["nbf"] = json!(jsonwebtoken::get_current_timestamp() + 300);
+        assert!(v
+            .validate(&token(Header::default(), not_yet_valid))
+            .is_err());
+
+        let mut roleless = claims();
+        roleless["roles"] = json!("something-else");
+        assert!(matches!(
+            v.validate(&token(Header::default(), roleless)),
+            Err(JwtError::NoRole)
+        ));
+    }
+
+    /// Key set holding SECRET under this key ID.
+    fn jwks(kid: &str) -> serde_json::Value {
+        json!({
+            "keys": [{
+                "kty": "oct",
+                "kid": kid,
+                "alg": "HS256",
+                "k": "YSB2ZXJ5IHNlY3JldCBzZWNyZXQ"
+            }]
+        })
+    }
+
+    fn signed_with(kid: &str) -> String {
+        let header = Header {
+            kid: Some(String::from(kid)),
+            ..Default::default()
+        };
+        token(header, claims())
+    }
+
+    #[tokio::test]
+    async fn valid_token_with_jwks_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(jwks("key-1").to_string().as_bytes())
+            .unwrap();
+        let location = file.path().to_string_lossy().to_string();
+        let v = validator(KeySource::Jwks {
+            keys: RwLock::new(load_jwks(&location).await.unwrap()),
+            location,
+        });
+
+        let principal = v.validate(&signed_with("key-1")).unwrap();
+        assert_eq!(principal.subject, "some-service");
+        assert!(matches!(
+            v.validate(&signed_with("key-2")),
+            Err(JwtError::UnknownKey)
+        ));
+    }
+
+    #[tokio::test]
+    async fn loads_and_refreshes_jwks_over_http() {
+        // Serves whichever key set is current, as an identity provider rotating keys would.
+        let served = Arc::new(Mutex::new(jwks("key-1")));
+        let app = Router::new()
+            .route(
+                "/jwks.json",
+                get(
+                    |State(served): State<Arc<Mutex<serde_json::Value>>>| async move {
+                        Json(served.lock().unwrap().clone())
+                    },
+                ),
+            )
+            .with_state(served.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let location = format!("http://{address}/jwks.json");
+        let v = validator(KeySource::Jwks {
+            keys: RwLock::new(load_jwks(&location).await.unwrap()),
+            location,
+        });
+        assert!(v.validate(&signed_with("key-1")).is_ok());
+        assert!(matches!(
+            v.validate(&signed_with("key-2")),
+            Err(JwtError::UnknownKey)
+        ));
+
+        *served.lock().unwrap() = jwks("key-2");
+        v.refresh_jwks().await.unwrap();
+        assert!(v.validate(&signed_with("key-2")).is_ok());
+        assert!(matches!(
+            v.validate(&signed_with("key-1")),
+            Err(JwtError::UnknownKey)
+        ));
+
+        assert!(matches!(
+            load_jwks(&format!("http://{address}/missing.json")).await,
+            Err(JwtError::Jwks(_))
+        ));
+    }
+}
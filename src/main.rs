@@ -1,78 +1,768 @@
+mod auth;
+mod body_limit;
+mod cache;
 mod core;
+mod health;
+mod jwt;
+mod manage;
+mod metrics;
+mod migrations;
 mod models;
+mod openapi;
+mod problem;
+mod rate_limit;
+mod replica;
+mod request_id;
+mod routes;
 mod schema;
+mod settings;
 mod storage;
+mod telemetry;
+mod timeout;
+mod tls;
+mod versions;
 
-use crate::storage::{create_database_connection_pool, FacilitiesFilter};
-use axum::extract::{Path, Query};
+use crate::body_limit::BodyLimits;
+use crate::cache::FacilityCache;
+use crate::problem::Problem;
+use crate::replica::{ReadFrom, Replica};
+use crate::storage::{
+    create_database_connection_pool, create_replica_connection_pool, FacilitiesFilter,
+    FacilitiesSearch, OnConflict, SpatialIndex, WriteOptions, WriteOutcome, WriteReport,
+};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, MatchedPath, Path, Query, Request};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use axum::middleware;
+use axum::serve::ListenerExt;
+use axum::ServiceExt;
 use axum::{
-    extract::State, http::StatusCode, routing::delete, routing::get, routing::post, Json, Router,
+    extract::State, http::StatusCode, routing::delete, routing::get, routing::post, Extension,
+    Json, Router,
 };
-use deadpool_diesel::postgres::Pool;
+use clap::{Parser, Subcommand};
+use deadpool_diesel::postgres::{Object, Pool, PoolError};
 use dotenvy::dotenv;
-use std::env;
-use tower_http::trace::TraceLayer;
-use tracing::{debug, error, info};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use jwt::{JwtValidator, KeySource};
+use manage::MigrateAction;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use rate_limit::{AuthFailures, RateLimits, Store};
+use settings::{
+    AllowedOrigins, CorsSettings, JwtKeySettings, JwtSettings, MigrationMode, RateLimitStoreKind,
+    Settings, SettingsArgs,
+};
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tower::Layer;
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::decompression::RequestDecompressionLayer;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::{debug, error, info, warn, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
+
+/// Characters to percent-encode when putting a UID into a URL path segment.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
 
 #[derive(Clone)]
 struct AppState {
     conn_pool: Pool,
+    /// Read replica for reads that can lag a little behind writes, if configured.
+    replica: Option<Replica>,
+    /// Recently read facilities, if caching is on.
+    cache: Option<Arc<FacilityCache>>,
+    /// Decimal places to round coordinates to in responses, if any.
+    coordinate_precision: Option<u32>,
+    spatial_index: SpatialIndex,
+    /// Hash of the bootstrap admin API key from settings, if any.
+    admin_api_key_hash: Option<String>,
+    /// Validator for bearer tokens from other services, if configured.
+    jwt: Option<Arc<JwtValidator>>,
+    /// Per-client request rate limits, if configured.
+    rate_limits: Option<Arc<RateLimits>>,
+    /// Request body size limits per route.
+    body_limits: Arc<BodyLimits>,
+    /// How long requests may take before they're answered with 504 Gateway Timeout.
+    request_timeout: Duration,
+    /// Set once shutdown starts, so readiness checks fail while requests drain.
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl AppState {
+    /// Prepare a facility for sending to clients.
+    fn present(&self, facility: core::Facility) -> core::Facility {
+        match self.coordinate_precision {
+            Some(decimals) => facility.with_coordinate_precision(decimals),
+            None => facility,
+        }
+    }
+
+    /// Get a connection to read with, from the replica unless the client wrote recently.
+    ///
+    /// Falls back to the primary when the replica has no connection to give.
+    async fn read_connection(&self, read_from: ReadFrom) -> Result<Object, PoolError> {
+        if let (Some(replica), ReadFrom::Replica) = (&self.replica, read_from) {
+            match replica.pool.get().await {
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    warn!("reading from primary, error collecting client from replica pool {e:?}")
+                }
+            }
+        }
+        self.conn_pool.get().await
+    }
+
+    /// Forget cached reads that a write to these facilities changed.
+    fn invalidate_cache<'a>(&self, uids: impl IntoIterator<Item = &'a str>) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(uids);
+        }
+    }
+}
+
+/// Status for failing to get a pooled connection: 503 when none came free in time, so clients retry, or 500.
+fn pool_error_status(e: &PoolError) -> StatusCode {
+    match e {
+        PoolError::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// A fast toy web API for facilities.
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    settings: SettingsArgs,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API. This is the default.
+    Serve,
+    /// Apply, revert or list database migrations.
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+    /// Import facilities from a JSON array file, or "-" for stdin.
+    Import {
+        file: PathBuf,
+        /// What to do with facilities already stored: error, ignore or update.
+        #[arg(long, default_value = "error", value_parser = manage::parse_on_conflict)]
+        on_conflict: OnConflict,
+    },
+    /// Export every facility to a JSON array file, or "-" for stdout.
+    Export { file: PathBuf },
+    /// Create an API key and print its token.
+    CreateApiKey {
+        #[arg(long)]
+        name: String,
+        /// read, write or admin.
+        #[arg(long, value_parser = manage::parse_scope)]
+        scope: core::Scope,
+    },
+    /// Check settings and exit.
+    CheckConfig,
 }
 
 #[tokio::main]
 async fn main() {
-    // Init tracing
-    tracing_subscriber::fmt::init();
-    info!("starting server");
-
     // Read in settings.
     dotenv().ok();
-    let database_url =
-        env::var("DATABASE_URL").expect("required DATABASE_URL environment variable is not set");
-    let host = env::var("HOST").expect("required HOST environment variable is not set");
-    let port = env::var("PORT").expect("required PORT environment variable is not set");
-    let server_url = format!("{host}:{port}");
+    let cli = Cli::parse();
+    let settings = match Settings::load(cli.settings) {
+        Ok(s) => s,
+        Err(e) => {
+            eprint!("{e}");
+            std::process::exit(2);
+        }
+    };
+    let command = cli.command.unwrap_or(Command::Serve);
+
+    // Init tracing. Only the server logs to stdout, leaving it free for other commands' output.
+    let writer = match command {
+        Command::Serve => BoxMakeWriter::new(std::io::stdout),
+        _ => BoxMakeWriter::new(std::io::stderr),
+    };
+    let tracer_provider =
+        match telemetry::init_tracing(&settings.log, settings.telemetry.as_ref(), writer) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        };
+
+    let result = match command {
+        Command::Serve => serve(settings).await,
+        Command::Migrate { action } => manage::migrate(&settings, action).await,
+        Command::Import { file, on_conflict } => {
+            manage::import(&settings, &file, on_conflict).await
+        }
+        Command::Export { file } => manage::export(&settings, &file).await,
+        Command::CreateApiKey { name, scope } => {
+            manage::create_api_key(&settings, name, scope).await
+        }
+        Command::CheckConfig => check_config(&settings).await,
+    };
+    if let Err(e) = &result {
+        error!("{e}");
+    }
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("error flushing spans {e}");
+        }
+    }
+    // Exit without waiting on storage calls abandoned after the drain timeout.
+    // Postgres rolls back their transactions when the connections drop.
+    std::process::exit(if result.is_err() { 1 } else { 0 });
+}
+
+/// Check settings that can only be checked by trying them, like loading JWT keys.
+async fn check_config(settings: &Settings) -> Result<(), String> {
+    if let Some(jwt_settings) = &settings.jwt {
+        jwt_validator(jwt_settings).await?;
+    }
+    if let Some(tls_settings) = &settings.server.tls {
+        tls::TlsConfig::load(tls_settings)?;
+    }
+    println!("settings are valid");
+    Ok(())
+}
+
+/// Run the API server until it fails.
+async fn serve(settings: Settings) -> Result<(), String> {
+    info!("starting server");
 
     let conn_pool =
-        create_database_connection_pool(database_url, 5).expect("unable to connect to database");
-    // Here is where we'd do automated migrations if we're doing that.
+        create_database_connection_pool(&settings.database, settings.database.pool_size)
+            .map_err(|e| format!("unable to connect to database {e}"))?;
     debug!("setup database connection pool");
 
-    let state = AppState { conn_pool };
+    migrate_on_start(&conn_pool, settings.database.migrations).await?;
 
-    let app = Router::new()
-        .route("/facilities", post(post_facility))
+    let replica = create_replica_connection_pool(&settings.database, settings.database.pool_size)
+        .transpose()
+        .map_err(|e| format!("unable to connect to read replica {e}"))?
+        .map(|pool| Replica {
+            pool,
+            read_your_writes: settings.database.read_your_writes,
+        });
+    if replica.is_some() {
+        info!("reading from replica");
+    }
+
+    let admin_api_key_hash = settings.admin_api_key.as_deref().map(auth::hash_secret);
+    let jwt = match &settings.jwt {
+        Some(jwt_settings) => {
+            Some(Arc::new(jwt_validator(jwt_settings).await.map_err(
+                |e| format!("unable to set up bearer token validation {e}"),
+            )?))
+        }
+        None => None,
+    };
+    if let (Some(jwt), Some(JwtKeySettings::Jwks(location, refresh))) =
+        (&jwt, settings.jwt.as_ref().map(|j| &j.key))
+    {
+        info!("validating bearer tokens against JWKS {location:?}");
+        tokio::spawn(refresh_jwks_periodically(jwt.clone(), *refresh));
+    }
+    let rate_limit_store = settings
+        .rate_limit
+        .as_ref()
+        .map(|rate_limit| match rate_limit.store {
+            RateLimitStoreKind::Postgres => {
+                create_database_connection_pool(&settings.database, 2).map(Store::postgres)
+            }
+            RateLimitStoreKind::Memory => Ok(Store::memory()),
+        })
+        .transpose()
+        .map_err(|e| format!("unable to connect to rate limit database {e}"))?;
+    let rate_limits = settings
+        .rate_limit
+        .zip(rate_limit_store)
+        .map(|(rate_limit, store)| {
+            Arc::new(RateLimits {
+                default: rate_limit.default,
+                // Overrides from before trailing slashes were trimmed, like "GET /facilities/", still apply.
+                routes: rate_limit
+                    .routes
+                    .into_iter()
+                    .map(|(route, policy)| (rate_limit::normalize_route(&route), policy))
+                    .collect(),
+                store,
+                auth_failures: AuthFailures::new(rate_limit.auth_failures),
+            })
+        });
+    if let Some(rate_limits) = &rate_limits {
+        tokio::spawn(rate_limit::delete_full_buckets_periodically(
+            rate_limits.clone(),
+        ));
+    }
+    let spatial_index = if settings.use_postgis {
+        conn_pool
+            .get()
+            .await
+            .map_err(|e| format!("unable to get database connection {e}"))?
+            .interact(storage::detect_spatial_index)
+            .await
+            .map_err(|e| format!("error interacting through connection pool {e:?}"))?
+            .map_err(|e| format!("unable to inspect database for PostGIS support {e}"))?
+    } else {
+        SpatialIndex::Plain
+    };
+    info!("using {spatial_index:?} spatial index");
+
+    let cache = settings.cache.as_ref().map(|cache_settings| {
+        // Reads from a lagging replica right after a write may miss it, so aren't cached.
+        let settle = replica
+            .as_ref()
+            .map(|r| r.read_your_writes)
+            .unwrap_or_default();
+        Arc::new(FacilityCache::new(cache_settings, settle))
+    });
+    let state = AppState {
+        conn_pool,
+        replica,
+        cache,
+        coordinate_precision: settings.coordinate_precision,
+        spatial_index,
+        admin_api_key_hash,
+        jwt,
+        rate_limits,
+        body_limits: Arc::new(BodyLimits {
+            default: settings.body_limit.default,
+            routes: settings
+                .body_limit
+                .routes
+                .into_iter()
+                .map(|(route, size)| (rate_limit::normalize_route(&route), size))
+                .collect(),
+        }),
+        request_timeout: settings.server.request_timeout,
+        shutting_down: Arc::new(AtomicBool::new(false)),
+    };
+    // Every pool, closed once requests are done with them.
+    let pools: Vec<Pool> = [
+        Some(&state.conn_pool),
+        state.replica.as_ref().map(|r| &r.pool),
+        state.rate_limits.as_ref().and_then(|r| r.store.pool()),
+    ]
+    .into_iter()
+    .flatten()
+    .cloned()
+    .collect();
+    let shutting_down = state.shutting_down.clone();
+    tokio::spawn(metrics::count_facilities_periodically(state.clone()));
+
+    if let Some(metrics_url) = settings.server.metrics_address() {
+        let listener = tokio::net::TcpListener::bind(&metrics_url)
+            .await
+            .map_err(|e| format!("unable to listen on {metrics_url:?} {e}"))?;
+        let metrics_app = Router::new()
+            .route("/metrics", get(metrics::metrics))
+            .with_state(state.clone());
+        info!("serving metrics on {metrics_url:?}");
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, metrics_app).await {
+                error!("error serving metrics {e}");
+            }
+        });
+    }
+
+    let app = app(state, settings.cors.as_ref());
+    debug!("setup app routes");
+
+    let tls = match &settings.server.tls {
+        Some(tls_settings) => {
+            let tls = Arc::new(tls::TlsConfig::load(tls_settings)?);
+            tokio::spawn(tls.clone().reload_on_change());
+            Some(tls)
+        }
+        None => None,
+    };
+
+    let server_url = settings.server.address();
+    let listener = tokio::net::TcpListener::bind(&server_url)
+        .await
+        .map_err(|e| format!("unable to listen on {server_url:?} {e}"))?;
+    let drain_started = Arc::new(Notify::new());
+    // Trailing slashes are trimmed before routing, so this wraps the router instead of being one of its layers.
+    let app = middleware::from_fn(routes::trim_trailing_slash).layer(app);
+    let app = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app);
+    let shutdown = {
+        let drain_started = drain_started.clone();
+        let delay = settings.server.shutdown_delay;
+        let drain_timeout = settings.server.shutdown_timeout;
+        async move {
+            shutdown_signal().await;
+            // Keep taking requests for a while, so load balancers see /readyz fail before connections get refused.
+            shutting_down.store(true, Ordering::SeqCst);
+            info!(
+                "shutting down, reporting unavailable for {} seconds before refusing connections",
+                delay.as_secs()
+            );
+            tokio::time::sleep(delay).await;
+            info!(
+                "refusing connections, letting in-flight requests finish for up to {} seconds",
+                drain_timeout.as_secs()
+            );
+            drain_started.notify_one();
+        }
+    };
+    let server: Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>> = match tls {
+        Some(tls) => {
+            info!("listening on {server_url:?} with TLS");
+            let listener = tls::TlsListener::new(listener, tls)
+                .map_err(|e| format!("unable to listen on {server_url:?} {e}"))?
+                // Does nothing to connections, but gives the listener the client address connect info.
+                .tap_io(|_| {});
+            Box::pin(
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .into_future(),
+            )
+        }
+        None => {
+            info!("listening on {server_url:?}");
+            Box::pin(
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(shutdown)
+                    .into_future(),
+            )
+        }
+    };
+
+    // Stop waiting for stragglers once the drain timeout is up.
+    let result = tokio::select! {
+        result = server => result.map_err(|e| format!("error serving {e}")),
+        _ = async {
+            drain_started.notified().await;
+            tokio::time::sleep(settings.server.shutdown_timeout).await;
+        } => {
+            warn!("drain timeout elapsed, abandoning in-flight requests");
+            Ok(())
+        }
+    };
+
+    for pool in pools {
+        pool.close();
+    }
+    info!("closed database connection pools");
+    result
+}
+
+/// Routes of version 1 of the API, nested under /v1.
+///
+/// A later version with its own representations gets its own routes, nested beside these over the same state.
+fn v1_routes(state: &AppState) -> Router<AppState> {
+    let read_routes = Router::new()
+        .route("/facility-searches", post(search_facilities))
         .route("/facilities/{uid}", get(get_facility))
-        .route("/facilities/", get(get_facilities))
+        .route("/facilities", get(get_facilities))
+        .route_layer(middleware::from_fn(cache::cache_control))
+        .route_layer(middleware::from_fn(auth::require_read));
+    let write_routes = Router::new()
+        .route("/facilities", post(post_facility))
+        .route("/facility-imports", post(import_facilities))
         .route("/facilities/{uid}", delete(delete_facility))
-        .layer(TraceLayer::new_for_http())
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            replica::remember_writes,
+        ))
+        .route_layer(middleware::from_fn(auth::require_write));
+    let admin_routes = Router::new()
+        .route("/api-keys", post(post_api_key).get(get_api_keys))
+        .route("/api-keys/{id}", delete(delete_api_key))
+        .route_layer(middleware::from_fn(auth::require_admin));
+
+    Router::new()
+        .merge(read_routes)
+        .merge(write_routes)
+        .merge(admin_routes)
+}
+
+/// Build the app's routes and middleware.
+fn app(state: AppState, cors: Option<&CorsSettings>) -> Router {
+    let app = Router::new()
+        .nest(versions::V1, v1_routes(&state))
+        // The unversioned paths from before versioning, kept working until their sunset.
+        .merge(v1_routes(&state).route_layer(middleware::from_fn(versions::deprecated_alias)))
+        // Scrapes on the main port need admin credentials. METRICS_PORT serves them without.
+        .route(
+            "/metrics",
+            get(metrics::metrics).route_layer(middleware::from_fn(auth::require_admin)),
+        )
+        // Set before adding layers, which only wrap the fallbacks already there.
+        .method_not_allowed_fallback(routes::method_not_allowed)
+        // Limits count decompressed bytes, replacing axum's fixed default.
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            body_limit::limit,
+        ))
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestDecompressionLayer::new())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::authenticate,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.request_timeout,
+            timeout::limit,
+        ))
+        .layer(middleware::from_fn(metrics::track))
+        .layer(
+            TraceLayer::new_for_http()
+                .make_span_with(|request: &Request| {
+                    let span = tracing::info_span!(
+                        "request",
+                        method = %request.method(),
+                        uri = %request.uri(),
+                        route = request.extensions().get::<MatchedPath>().map(|p| p.as_str()),
+                        version = ?request.version(),
+                        request_id = request
+                            .headers()
+                            .get(&request_id::REQUEST_ID_HEADER)
+                            .and_then(|v| v.to_str().ok()),
+                        client_ip = request
+                            .extensions()
+                            .get::<ConnectInfo<SocketAddr>>()
+                            .map(|ConnectInfo(addr)| tracing::field::display(addr.ip())),
+                        api_key_id = tracing::field::Empty,
+                        subject = tracing::field::Empty,
+                    );
+                    // Continue the client's trace, if it sent one.
+                    let _ = span.set_parent(telemetry::remote_context(request.headers()));
+                    span
+                })
+                // One access log line per request, carrying the request span's fields.
+                .on_response(
+                    DefaultOnResponse::new()
+                        .level(Level::INFO)
+                        .latency_unit(LatencyUnit::Millis),
+                ),
+        )
+        // Probes and docs are added last so they skip authentication, rate limits and tracing.
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .merge(SwaggerUi::new(routes::DOCS_PATH).url("/openapi.json", openapi::ApiDoc::openapi()))
+        .method_not_allowed_fallback(routes::method_not_allowed)
+        .layer(middleware::from_fn(request_id::propagate))
+        // Compress whatever the client accepts, after problem bodies get their request IDs.
+        .layer(CompressionLayer::new())
         .with_state(state);
-    debug!("setup app routes");
+    // Outermost, so preflights are answered before authentication and errors carry CORS headers too.
+    match cors {
+        Some(cors) => app.layer(cors_layer(cors)),
+        None => app,
+    }
+}
 
-    info!("listening on {server_url:?}");
-    let listener = tokio::net::TcpListener::bind(server_url).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// Build the CORS layer from settings.
+fn cors_layer(cors: &CorsSettings) -> CorsLayer {
+    let allow_origin = match &cors.allowed_origins {
+        AllowedOrigins::Any => AllowOrigin::any(),
+        AllowedOrigins::List(origins) => AllowOrigin::list(origins.clone()),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(cors.allowed_methods.clone())
+        .allow_headers(cors.allowed_headers.clone())
+        .allow_credentials(cors.allow_credentials)
+        .max_age(cors.max_age)
+        // Let browser clients read the headers the API's responses carry.
+        .expose_headers([
+            header::LOCATION,
+            header::CONTENT_LOCATION,
+            header::LINK,
+            header::RETRY_AFTER,
+            request_id::REQUEST_ID_HEADER.clone(),
+            HeaderName::from_static("ratelimit-limit"),
+            HeaderName::from_static("ratelimit-remaining"),
+            HeaderName::from_static("ratelimit-reset"),
+            HeaderName::from_static("ratelimit-policy"),
+            versions::DEPRECATION_HEADER.clone(),
+            versions::SUNSET_HEADER.clone(),
+        ])
+}
+
+/// Wait for SIGTERM, as sent by container runtimes, or SIGINT from a terminal.
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("unable to listen for SIGINT");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
+/// Apply or check for pending migrations as the settings say.
+async fn migrate_on_start(conn_pool: &Pool, mode: MigrationMode) -> Result<(), String> {
+    if mode == MigrationMode::Skip {
+        return Ok(());
+    }
+    let client = conn_pool
+        .get()
+        .await
+        .map_err(|e| format!("unable to get database connection for migrations {e}"))?;
+    let result = client
+        .interact(move |conn| match mode {
+            MigrationMode::Check => migrations::pending_migrations(conn),
+            _ => migrations::run_pending_migrations(conn),
+        })
+        .await
+        .map_err(|e| format!("error interacting through connection pool {e:?}"))?;
+    // Migrating lifted the connection's statement timeout, so it mustn't go back to serving requests.
+    let _ = Object::take(client);
+    match (mode, result) {
+        (_, Err(e)) => Err(format!("error running migrations {e}")),
+        (MigrationMode::Check, Ok(pending)) if !pending.is_empty() => Err(format!(
+            "refusing to start with pending migrations {}",
+            pending.join(", ")
+        )),
+        (MigrationMode::Check, Ok(_)) => {
+            debug!("no pending migrations");
+            Ok(())
+        }
+        (_, Ok(applied)) => {
+            for version in applied {
+                info!("applied migration {version}");
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Build a bearer token validator from JWT settings, loading its keys.
+async fn jwt_validator(settings: &JwtSettings) -> Result<JwtValidator, String> {
+    let keys = match &settings.key {
+        JwtKeySettings::Secret(secret) => KeySource::Static(
+            DecodingKey::from_secret(secret.as_bytes()),
+            Algorithm::HS256,
+        ),
+        JwtKeySettings::PublicKeyFile(path, algorithm) => {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("unable to read {}: {e}", path.display()))?;
+            let key = match algorithm {
+                Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&pem),
+                Algorithm::EdDSA => DecodingKey::from_ed_pem(&pem),
+                _ => DecodingKey::from_rsa_pem(&pem),
+            }
+            .map_err(|e| {
+                format!(
+                    "{} is not a {algorithm:?} PEM public key: {e}",
+                    path.display()
+                )
+            })?;
+            KeySource::Static(key, *algorithm)
+        }
+        JwtKeySettings::Jwks(location, _) => KeySource::Jwks {
+            keys: RwLock::new(jwt::load_jwks(location).await.map_err(|e| e.to_string())?),
+            location: location.clone(),
+        },
+    };
+
+    Ok(JwtValidator {
+        issuer: settings.issuer.clone(),
+        audience: settings.audience.clone(),
+        roles_claim: settings.roles_claim.clone(),
+        keys,
+    })
+}
+
+/// Keep a JWKS-backed validator's keys fresh so rotated keys are picked up.
+async fn refresh_jwks_periodically(jwt: Arc<JwtValidator>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick is immediate and the keys were just loaded.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match jwt.refresh_jwks().await {
+            Ok(()) => debug!("refreshed JWKS"),
+            Err(e) => error!("error refreshing JWKS, keeping previous keys {e}"),
+        }
+    }
 }
 
 /// Handle request to create a new facility.
+#[utoipa::path(
+    post,
+    path = "/facilities",
+    tag = "facilities",
+    params(WriteOptions),
+    request_body = core::Facility,
+    responses(
+        (status = 201, description = "Facility created", body = core::Facility,
+            headers(("Location" = String, description = "Where to get the new facility"))),
+        (status = 200, description = "Existing facility updated, or already the same", body = core::Facility,
+            headers(("Content-Location" = String, description = "Where to get the facility"))),
+        (status = 409, description = "A facility with this UID already exists", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 async fn post_facility(
     State(state): State<AppState>,
+    Extension(principal): Extension<auth::Principal>,
+    Query(options): Query<WriteOptions>,
     Json(payload): Json<core::Facility>,
-) -> Result<Json<core::Facility>, StatusCode> {
-    debug!("received request to post {payload:?}");
+) -> Result<(StatusCode, HeaderMap, Json<core::Facility>), StatusCode> {
+    debug!("received request to post {payload:?} with {options:?}");
 
     let client_result = state.conn_pool.get().await;
     let client = match client_result {
         Ok(r) => r,
         Err(e) => {
             error!("error collecting client from connection pool {e:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(pool_error_status(&e));
         }
     };
 
-    let interaction_result = client
-        .interact(|conn| storage::write_facility(conn, payload))
-        .await;
+    let interaction_result = metrics::timed(
+        "write_facility",
+        client.interact(move |conn| storage::write_facility(conn, payload, options.on_conflict)),
+    )
+    .await;
     let new_facility_result = match interaction_result {
         Ok(r) => r,
         Err(e) => {
@@ -82,7 +772,32 @@ async fn post_facility(
     };
 
     match new_facility_result {
-        Ok(new_facility) => Ok(Json(new_facility)), // TODO: Should be StatusCode::CREATED. Check it.
+        Ok((new_facility, outcome)) => {
+            info!(
+                "facility {:?} was {outcome:?} by {:?}",
+                new_facility.uid, principal.subject
+            );
+            state.invalidate_cache([new_facility.uid.as_str()]);
+            let mut headers = HeaderMap::new();
+            let location = format!(
+                "{}/facilities/{}",
+                versions::V1,
+                utf8_percent_encode(&new_facility.uid, PATH_SEGMENT)
+            );
+            // Percent-encoded paths are always valid header values.
+            let location = HeaderValue::try_from(location).unwrap();
+            let status = match outcome {
+                WriteOutcome::Inserted => {
+                    headers.insert(header::LOCATION, location);
+                    StatusCode::CREATED
+                }
+                WriteOutcome::Updated | WriteOutcome::Unchanged => {
+                    headers.insert(header::CONTENT_LOCATION, location);
+                    StatusCode::OK
+                }
+            };
+            Ok((status, headers, Json(state.present(new_facility))))
+        }
         Err(diesel::result::Error::DatabaseError(
             diesel::result::DatabaseErrorKind::UniqueViolation,
             _,
@@ -94,25 +809,112 @@ async fn post_facility(
     }
 }
 
+/// Handle request to create or update many facilities at once.
+#[utoipa::path(
+    post,
+    path = "/facility-imports",
+    tag = "facilities",
+    params(WriteOptions),
+    request_body = Vec<core::Facility>,
+    responses(
+        (status = 200, description = "Every facility was written. Reports what happened to each", body = Vec<WriteReport>),
+        (status = 409, description = "A facility already exists, so nothing was written", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+async fn import_facilities(
+    State(state): State<AppState>,
+    Extension(principal): Extension<auth::Principal>,
+    Query(options): Query<WriteOptions>,
+    Json(payload): Json<Vec<core::Facility>>,
+) -> Result<Json<Vec<WriteReport>>, StatusCode> {
+    debug!(
+        "received request to import {} facilities with {options:?}",
+        payload.len()
+    );
+
+    let client_result = state.conn_pool.get().await;
+    let client = match client_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error collecting client from connection pool {e:?}");
+            return Err(pool_error_status(&e));
+        }
+    };
+
+    let interaction_result = metrics::timed(
+        "write_facilities",
+        client.interact(move |conn| storage::write_facilities(conn, payload, options.on_conflict)),
+    )
+    .await;
+    let import_result = match interaction_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error interacting through connection pool {e:?}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    match import_result {
+        Ok(reports) => {
+            info!(
+                "{} facilities were imported by {:?}",
+                reports.len(),
+                principal.subject
+            );
+            state.invalidate_cache(reports.iter().map(|r| r.uid.as_str()));
+            Ok(Json(reports))
+        }
+        Err(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => Err(StatusCode::CONFLICT),
+        Err(e) => {
+            error!("error importing facilities to database {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 /// Handle request for to get an existing facility.
+#[utoipa::path(
+    get,
+    path = "/facilities/{uid}",
+    tag = "facilities",
+    params(("uid" = String, Path, description = "UID of the facility")),
+    responses(
+        (status = 200, description = "The facility", body = core::Facility),
+        (status = 404, description = "No facility has this UID", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 async fn get_facility(
     Path(uid): Path<String>,
     State(state): State<AppState>,
+    read_from: ReadFrom,
 ) -> Result<Json<core::Facility>, StatusCode> {
     debug!("received request to get facility {uid:?}");
 
-    let client_result = state.conn_pool.get().await;
+    if let Some(cache) = &state.cache {
+        if let Some(facility) = cache.facility(&uid, read_from) {
+            return Ok(Json(state.present(facility)));
+        }
+    }
+    let read_started = Instant::now();
+    let client_result = state.read_connection(read_from).await;
     let client = match client_result {
         Ok(r) => r,
         Err(e) => {
             error!("error collecting client from connection pool {e:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(pool_error_status(&e));
         }
     };
 
-    let interaction_result = client
-        .interact(|conn| storage::read_facility(conn, uid))
-        .await;
+    let interaction_result = metrics::timed(
+        "read_facility",
+        client.interact(|conn| storage::read_facility(conn, uid)),
+    )
+    .await;
     let read_facility_result = match interaction_result {
         Ok(r) => r,
         Err(e) => {
@@ -122,7 +924,12 @@ async fn get_facility(
     };
 
     match read_facility_result {
-        Ok(matching_facility) => Ok(Json(matching_facility)),
+        Ok(matching_facility) => {
+            if let Some(cache) = &state.cache {
+                cache.insert_facility(matching_facility.clone(), read_started);
+            }
+            Ok(Json(state.present(matching_facility)))
+        }
         Err(diesel::result::Error::NotFound) => Err(StatusCode::NOT_FOUND),
         Err(e) => {
             error!("error getting facility from database {e:?}");
@@ -132,26 +939,49 @@ async fn get_facility(
 }
 
 /// Handle request to list facilities.
+#[utoipa::path(
+    get,
+    path = "/facilities",
+    tag = "facilities",
+    params(FacilitiesFilter),
+    responses(
+        (status = 200, description = "Facilities matching the filter", body = Vec<core::Facility>),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 async fn get_facilities(
     State(state): State<AppState>,
+    read_from: ReadFrom,
     Query(params): Query<FacilitiesFilter>,
 ) -> Result<Json<Vec<core::Facility>>, StatusCode> {
     debug!("Received request to get facilities with filter {params:?}");
 
-    let client_result = state.conn_pool.get().await;
+    let cache_key = params.cache_key();
+    if let Some(cache) = &state.cache {
+        if let Some(facilities) = cache.list(&cache_key, read_from) {
+            return Ok(Json(
+                facilities.into_iter().map(|f| state.present(f)).collect(),
+            ));
+        }
+    }
+    let read_started = Instant::now();
+    let spatial_index = state.spatial_index;
+    let client_result = state.read_connection(read_from).await;
     let client = match client_result {
         Ok(r) => r,
         Err(e) => {
             return {
                 error!("error collecting client from connection pool {e:?}");
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
+                Err(pool_error_status(&e))
             }
         }
     };
 
-    let interaction_result = client
-        .interact(|conn| storage::list_facilities(conn, params))
-        .await;
+    let interaction_result = metrics::timed(
+        "list_facilities",
+        client.interact(move |conn| storage::list_facilities(conn, params, spatial_index)),
+    )
+    .await;
     let list_facilities_result = match interaction_result {
         Ok(r) => r,
         Err(e) => {
@@ -161,7 +991,14 @@ async fn get_facilities(
     };
 
     match list_facilities_result {
-        Ok(facilities) => Ok(Json(facilities)),
+        Ok(facilities) => {
+            if let Some(cache) = &state.cache {
+                cache.insert_list(cache_key, facilities.clone(), read_started);
+            }
+            Ok(Json(
+                facilities.into_iter().map(|f| state.present(f)).collect(),
+            ))
+        }
         Err(e) => {
             error!("error listing facilities from database {e:?}");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
@@ -169,9 +1006,81 @@ async fn get_facilities(
     }
 }
 
+/// Handle request to list facilities inside a GeoJSON region.
+#[utoipa::path(
+    post,
+    path = "/facility-searches",
+    tag = "facilities",
+    request_body = FacilitiesSearch,
+    responses(
+        (status = 200, description = "Facilities inside the region, ordered by UID", body = Vec<core::Facility>),
+        (status = 422, description = "Without PostGIS, too many facilities fall within the region's bounding box", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+async fn search_facilities(
+    State(state): State<AppState>,
+    read_from: ReadFrom,
+    Json(search): Json<FacilitiesSearch>,
+) -> Result<Json<Vec<core::Facility>>, Problem> {
+    debug!("Received request to search facilities with {search:?}");
+
+    let spatial_index = state.spatial_index;
+    let client_result = state.read_connection(read_from).await;
+    let client = match client_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error collecting client from connection pool {e:?}");
+            return Err(Problem::new(pool_error_status(&e)));
+        }
+    };
+
+    let interaction_result = metrics::timed(
+        "search_facilities",
+        client.interact(move |conn| storage::search_facilities(conn, search, spatial_index)),
+    )
+    .await;
+    let search_facilities_result = match interaction_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error interacting through connection pool {e:?}");
+            return Err(Problem::new(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    match search_facilities_result {
+        Ok(facilities) => Ok(Json(
+            facilities.into_iter().map(|f| state.present(f)).collect(),
+        )),
+        Err(storage::SearchError::TooManyCandidates) => Err(Problem::new(
+            StatusCode::UNPROCESSABLE_ENTITY,
+        )
+        .with_detail(format!(
+            "more than {} facilities fall within the region's bounding box, so narrow it down with filters",
+            storage::MAX_SEARCH_CANDIDATES
+        ))),
+        Err(storage::SearchError::Storage(e)) => {
+            error!("error searching facilities in database {e:?}");
+            Err(Problem::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
 /// Handle request to delete a new facility.
+#[utoipa::path(
+    delete,
+    path = "/facilities/{uid}",
+    tag = "facilities",
+    params(("uid" = String, Path, description = "UID of the facility")),
+    responses(
+        (status = 204, description = "Facility deleted"),
+        (status = 404, description = "No facility has this UID", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 async fn delete_facility(
     State(state): State<AppState>,
+    Extension(principal): Extension<auth::Principal>,
     Path(uid): Path<String>,
 ) -> Result<StatusCode, StatusCode> {
     debug!("Received request to delete facility {uid:?}");
@@ -181,13 +1090,18 @@ async fn delete_facility(
         Ok(r) => r,
         Err(e) => {
             error!("error collecting client from connection pool {e:?}");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(pool_error_status(&e));
         }
     };
 
-    let interaction_result = client
-        .interact(|conn| storage::delete_facility(conn, uid))
-        .await;
+    let interaction_result = metrics::timed(
+        "delete_facility",
+        client.interact({
+            let uid = uid.clone();
+            |conn| storage::delete_facility(conn, uid)
+        }),
+    )
+    .await;
     let delete_result = match interaction_result {
         Ok(r) => r,
         Err(e) => {
@@ -197,7 +1111,11 @@ async fn delete_facility(
     };
 
     match delete_result {
-        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Ok(_) => {
+            info!("facility {uid:?} was deleted by {:?}", principal.subject);
+            state.invalidate_cache([uid.as_str()]);
+            Ok(StatusCode::NO_CONTENT)
+        }
         Err(diesel::result::Error::NotFound) => Err(StatusCode::NOT_FOUND),
         Err(e) => {
             error!("error deleting facility from database {e:?}");
@@ -207,3 +1125,549 @@ async fn delete_facility(
 }
 
 // TODO: Format error messages properly.
+
+/// Handle request to create a new API key.
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    tag = "api-keys",
+    request_body = auth::NewApiKey,
+    responses(
+        (status = 201, description = "API key created. The response holds the only copy of its token", body = auth::CreatedApiKey),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+async fn post_api_key(
+    State(state): State<AppState>,
+    Json(payload): Json<auth::NewApiKey>,
+) -> Result<(StatusCode, Json<auth::CreatedApiKey>), StatusCode> {
+    debug!("received request to create API key {payload:?}");
+
+    let client_result = state.conn_pool.get().await;
+    let client = match client_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error collecting client from connection pool {e:?}");
+            return Err(pool_error_status(&e));
+        }
+    };
+
+    let interaction_result = metrics::timed(
+        "create_api_key",
+        client.interact(|conn| auth::create_api_key(conn, payload)),
+    )
+    .await;
+    let create_result = match interaction_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error interacting through connection pool {e:?}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    match create_result {
+        Ok(created) => {
+            info!("created API key {:?}", created.api_key.id);
+            Ok((StatusCode::CREATED, Json(created)))
+        }
+        Err(e) => {
+            error!("error writing new API key to database {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Handle request to list API keys.
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    tag = "api-keys",
+    responses(
+        (status = 200, description = "Every API key, without tokens", body = Vec<core::ApiKey>),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+async fn get_api_keys(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<core::ApiKey>>, StatusCode> {
+    debug!("received request to list API keys");
+
+    let client_result = state.conn_pool.get().await;
+    let client = match client_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error collecting client from connection pool {e:?}");
+            return Err(pool_error_status(&e));
+        }
+    };
+
+    let interaction_result =
+        metrics::timed("list_api_keys", client.interact(storage::list_api_keys)).await;
+    let list_result = match interaction_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error interacting through connection pool {e:?}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    match list_result {
+        Ok(api_keys) => Ok(Json(api_keys)),
+        Err(e) => {
+            error!("error listing API keys from database {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Handle request to delete, and so revoke, an API key.
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{id}",
+    tag = "api-keys",
+    params(("id" = String, Path, description = "ID of the API key")),
+    responses(
+        (status = 204, description = "API key deleted, and so revoked"),
+        (status = 404, description = "No API key has this ID", body = Problem, content_type = "application/problem+json"),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+async fn delete_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    debug!("received request to delete API key {id:?}");
+
+    let client_result = state.conn_pool.get().await;
+    let client = match client_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error collecting client from connection pool {e:?}");
+            return Err(pool_error_status(&e));
+        }
+    };
+
+    let interaction_result = metrics::timed(
+        "delete_api_key",
+        client.interact(|conn| storage::delete_api_key(conn, id)),
+    )
+    .await;
+    let delete_result = match interaction_result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("error interacting through connection pool {e:?}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    match delete_result {
+        Ok(_) => Ok(StatusCode::NO_CONTENT),
+        Err(diesel::result::Error::NotFound) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("error deleting API key from database {e:?}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Method;
+    use settings::DatabaseSettings;
+    use std::collections::HashMap;
+    use tower::ServiceExt;
+
+    const ADMIN_API_KEY: &str = "test-admin-key";
+
+    fn test_state_database() -> DatabaseSettings {
+        DatabaseSettings {
+            url: String::from("postgresql://nobody@127.0.0.1:1/nothing"),
+            pool_size: 1,
+            pool_timeout: Duration::from_secs(1),
+            connect_timeout: Duration::from_secs(1),
+            statement_timeout: None,
+            replica_url: None,
+            read_your_writes: Duration::from_secs(5),
+            migrations: MigrationMode::Skip,
+        }
+    }
+
+    /// State for routing tests. The pool points nowhere, so handlers reaching storage fail fast.
+    fn test_state() -> AppState {
+        let database = test_state_database();
+        AppState {
+            conn_pool: create_database_connection_pool(&database, 1).unwrap(),
+            replica: None,
+            cache: None,
+            coordinate_precision: None,
+            spatial_index: SpatialIndex::Plain,
+            admin_api_key_hash: Some(auth::hash_secret(ADMIN_API_KEY)),
+            jwt: None,
+            rate_limits: None,
+            body_limits: Arc::new(BodyLimits {
+                default: 1024,
+                routes: HashMap::new(),
+            }),
+            request_timeout: Duration::from_secs(30),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// State reading from REPLICA_TEST_REPLICA_URL and writing to REPLICA_TEST_PRIMARY_URL, both migrated.
+    ///
+    /// They can be two unrelated databases, which shows which of them answered.
+    async fn replicated_state() -> AppState {
+        let url = |name| std::env::var(name).unwrap_or_else(|_| panic!("{name} names a database"));
+        let database = DatabaseSettings {
+            url: url("REPLICA_TEST_PRIMARY_URL"),
+            replica_url: Some(url("REPLICA_TEST_REPLICA_URL")),
+            ..test_state_database()
+        };
+        let conn_pool = create_database_connection_pool(&database, 1).unwrap();
+        let replica = create_replica_connection_pool(&database, 1)
+            .unwrap()
+            .unwrap();
+        for pool in [&conn_pool, &replica] {
+            pool.get()
+                .await
+                .unwrap()
+                .interact(migrations::run_pending_migrations)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        AppState {
+            conn_pool,
+            replica: Some(Replica {
+                pool: replica,
+                read_your_writes: database.read_your_writes,
+            }),
+            ..test_state()
+        }
+    }
+
+    fn admin_request(method: Method, path: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(auth::API_KEY_HEADER, ADMIN_API_KEY)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    /// Whether a concrete path like "/facilities/x" fits a spec path like "/facilities/{uid}".
+    fn fits(template: &str, path: &str) -> bool {
+        let template: Vec<&str> = template.split('/').collect();
+        let path: Vec<&str> = path.split('/').collect();
+        template.len() == path.len()
+            && template
+                .iter()
+                .zip(&path)
+                .all(|(t, p)| t == p || (t.starts_with('{') && !p.is_empty()))
+    }
+
+    #[tokio::test]
+    async fn openapi_matches_routes() {
+        let spec = openapi::ApiDoc::openapi();
+        let documented: Vec<(String, Method)> = spec
+            .paths
+            .paths
+            .iter()
+            .flat_map(|(path, item)| {
+                [
+                    (Method::GET, &item.get),
+                    (Method::POST, &item.post),
+                    (Method::PUT, &item.put),
+                    (Method::PATCH, &item.patch),
+                    (Method::DELETE, &item.delete),
+                ]
+                .into_iter()
+                .filter(|(_, operation)| operation.is_some())
+                .map(|(method, _)| (path.clone(), method))
+            })
+            .collect();
+        assert!(!documented.is_empty());
+
+        let app = app(test_state(), None);
+        for template in spec.paths.paths.keys() {
+            let path = template
+                .split('/')
+                .map(|s| if s.starts_with('{') { "x" } else { s })
+                .collect::<Vec<_>>()
+                .join("/");
+            for method in [
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+            ] {
+                let response = app
+                    .clone()
+                    .oneshot(admin_request(method.clone(), &path))
+                    .await
+                    .unwrap();
+                let status = response.status();
+                // Handlers can't reach storage here, so any 404 comes from the router.
+                let routed =
+                    status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED;
+                // Like the router, prefer the spec path with the most literal segments.
+                let best = spec
+                    .paths
+                    .paths
+                    .keys()
+                    .filter(|t| fits(t, &path))
+                    .min_by_key(|t| t.matches('{').count())
+                    .unwrap();
+                let expected = documented.iter().any(|(t, m)| *m == method && t == best);
+                assert_eq!(
+                    routed,
+                    expected,
+                    "{method} {path} answered {status}, but the spec says it is {}",
+                    if expected { "routed" } else { "not routed" }
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unversioned_aliases_are_deprecated() {
+        let app = app(test_state(), None);
+
+        let response = app
+            .clone()
+            .oneshot(admin_request(Method::GET, "/facilities"))
+            .await
+            .unwrap();
+        assert!(response.headers().contains_key("deprecation"));
+        assert!(response.headers().contains_key("sunset"));
+        assert_eq!(
+            response.headers()[header::LINK],
+            "</v1/facilities>; rel=\"successor-version\""
+        );
+
+        let response = app
+            .oneshot(admin_request(Method::GET, "/v1/facilities"))
+            .await
+            .unwrap();
+        assert!(!response.headers().contains_key("deprecation"));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs databases in REPLICA_TEST_PRIMARY_URL and REPLICA_TEST_REPLICA_URL"]
+    async fn reads_go_to_the_replica_unless_the_client_just_wrote() {
+        let state = replicated_state().await;
+        let (primary, replica) = (
+            state.conn_pool.clone(),
+            state.replica.as_ref().unwrap().pool.clone(),
+        );
+        let app = app(state, None);
+        let run = uuid::Uuid::new_v4().simple().to_string();
+        let (on_replica, written) = (format!("replica-{run}"), format!("written-{run}"));
+        let facility = |uid: &str| {
+            core::Facility::new(
+                uid.to_string(),
+                String::from("c"),
+                String::from("s"),
+                String::from("t"),
+                35.6,
+                -88.8,
+                chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                None,
+            )
+            .unwrap()
+        };
+        let get = |uid: &str, cookie: Option<&HeaderValue>| {
+            let mut request = admin_request(Method::GET, &format!("/v1/facilities/{uid}"));
+            if let Some(cookie) = cookie {
+                request.headers_mut().insert(header::COOKIE, cookie.clone());
+            }
+            app.clone().oneshot(request)
+        };
+
+        // Only the replica has this one, so finding it means the replica answered.
+        let stored = facility(&on_replica);
+        replica
+            .get()
+            .await
+            .unwrap()
+            .interact(|conn| storage::write_facility(conn, stored, storage::OnConflict::Error))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            get(&on_replica, None).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        let mut request = admin_request(Method::POST, "/v1/facilities");
+        request.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        *request.body_mut() = Body::from(serde_json::to_vec(&facility(&written)).unwrap());
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let set_cookie = response.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = HeaderValue::from_str(set_cookie.split(';').next().unwrap()).unwrap();
+        let uid = written.clone();
+        assert!(primary
+            .get()
+            .await
+            .unwrap()
+            .interact(|conn| storage::read_facility(conn, uid))
+            .await
+            .unwrap()
+            .is_ok());
+
+        // Without the cookie reads still go to the replica, which doesn't have the write.
+        assert_eq!(
+            get(&written, None).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        // With it they go to the primary, which has the write but not the replica's own facility.
+        assert_eq!(
+            get(&written, Some(&cookie)).await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            get(&on_replica, Some(&cookie)).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        for (pool, uid) in [(replica, on_replica), (primary, written)] {
+            pool.get()
+                .await
+                .unwrap()
+                .interact(|conn| storage::delete_facility(conn, uid))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn metrics_need_admin_credentials() {
+        let app = app(test_state(), None);
+        let response = app
+            .clone()
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .oneshot(admin_request(Method::GET, "/metrics"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn unsupported_methods_get_allow_and_problem() {
+        let response = app(test_state(), None)
+            .oneshot(admin_request(Method::PUT, "/v1/facilities"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET,HEAD,POST");
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["status"], 405);
+        assert!(problem["request_id"].is_string());
+    }
+
+    #[tokio::test]
+    async fn any_uid_reaches_its_facility() {
+        let app = app(test_state(), None);
+        for uid in ["import", "search"] {
+            for method in [Method::GET, Method::DELETE] {
+                let path = format!("/v1/facilities/{uid}");
+                let response = app
+                    .clone()
+                    .oneshot(admin_request(method.clone(), &path))
+                    .await
+                    .unwrap();
+                // Handlers can't reach storage here, so only the router answers 404 or 405.
+                assert!(
+                    ![StatusCode::NOT_FOUND, StatusCode::METHOD_NOT_ALLOWED]
+                        .contains(&response.status()),
+                    "{method} {path} answered {}",
+                    response.status()
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_cors_preflights_before_authenticating() {
+        let cors = CorsSettings {
+            allowed_origins: AllowedOrigins::List(vec![HeaderValue::from_static(
+                "https://map.example.com",
+            )]),
+            allowed_methods: vec![Method::GET, Method::POST],
+            allowed_headers: vec![header::CONTENT_TYPE],
+            allow_credentials: false,
+            max_age: Duration::from_secs(600),
+        };
+        let app = app(test_state(), Some(&cors));
+
+        let preflight = |origin: &'static str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/v1/facilities")
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app
+            .clone()
+            .oneshot(preflight("https://map.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://map.example.com"
+        );
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_MAX_AGE], "600");
+
+        let response = app
+            .oneshot(preflight("https://elsewhere.example.com"))
+            .await
+            .unwrap();
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn rejects_large_bodies_with_problem() {
+        let response = app(test_state(), None)
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/v1/facilities")
+                    .header(auth::API_KEY_HEADER, ADMIN_API_KEY)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(vec![b' '; 2048]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+    }
+}
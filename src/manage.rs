@@ -0,0 +1,156 @@
+use crate::auth;
+use crate::core::{self, Scope};
+use crate::migrations;
+use crate::settings::Settings;
+use crate::storage::{self, create_database_connection_pool, OnConflict, WriteOutcome};
+use deadpool_diesel::postgres::Pool;
+use diesel::PgConnection;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// What `migrate` should do.
+#[derive(clap::Subcommand, Clone, Copy, Debug)]
+pub enum MigrateAction {
+    /// Apply every pending migration.
+    Up,
+    /// Revert the most recently applied migration.
+    Down,
+    /// List applied and pending migrations.
+    Status,
+}
+
+/// Apply, revert or list migrations.
+pub async fn migrate(settings: &Settings, action: MigrateAction) -> Result<(), String> {
+    let lines = with_connection(settings, move |conn| {
+        let lines = match action {
+            MigrateAction::Up => migrations::run_pending_migrations(conn)?
+                .into_iter()
+                .map(|v| format!("applied {v}"))
+                .collect(),
+            MigrateAction::Down => vec![format!(
+                "reverted {}",
+                migrations::revert_last_migration(conn)?
+            )],
+            MigrateAction::Status => {
+                let mut lines: Vec<String> = migrations::applied_migrations(conn)?
+                    .into_iter()
+                    .map(|v| format!("applied {v}"))
+                    .collect();
+                lines.extend(
+                    migrations::pending_migrations(conn)?
+                        .into_iter()
+                        .map(|m| format!("pending {m}")),
+                );
+                lines
+            }
+        };
+        Ok::<_, migrations::MigrationError>(lines)
+    })
+    .await?
+    .map_err(|e| format!("error running migrations {e}"))?;
+
+    if lines.is_empty() {
+        println!("nothing to do");
+    }
+    for line in lines {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Import facilities from a JSON array in a file, or stdin if the path is "-".
+///
+/// Writes all or nothing, like POST /facility-imports.
+pub async fn import(
+    settings: &Settings,
+    path: &Path,
+    on_conflict: OnConflict,
+) -> Result<(), String> {
+    let mut contents = String::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_to_string(&mut contents)
+    } else {
+        std::fs::File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+    }
+    .map_err(|e| format!("unable to read {}: {e}", path.display()))?;
+    let facilities: Vec<core::Facility> = serde_json::from_str(&contents)
+        .map_err(|e| format!("{} is not a JSON array of facilities: {e}", path.display()))?;
+
+    let reports = with_connection(settings, move |conn| {
+        storage::write_facilities(conn, facilities, on_conflict)
+    })
+    .await?
+    .map_err(|e| format!("error importing facilities, nothing was written: {e}"))?;
+
+    let count = |outcome| reports.iter().filter(|r| r.outcome == outcome).count();
+    println!(
+        "inserted {}, updated {}, unchanged {}",
+        count(WriteOutcome::Inserted),
+        count(WriteOutcome::Updated),
+        count(WriteOutcome::Unchanged)
+    );
+    Ok(())
+}
+
+/// Export every facility as a JSON array to a file, or stdout if the path is "-".
+pub async fn export(settings: &Settings, path: &Path) -> Result<(), String> {
+    let facilities = with_connection(settings, storage::export_facilities)
+        .await?
+        .map_err(|e| format!("error reading facilities from database {e}"))?;
+    let json = serde_json::to_string_pretty(&facilities).map_err(|e| e.to_string())?;
+
+    if path == Path::new("-") {
+        writeln!(std::io::stdout(), "{json}")
+    } else {
+        std::fs::write(path, json)
+    }
+    .map_err(|e| format!("unable to write {}: {e}", path.display()))?;
+    eprintln!("exported {} facilities", facilities.len());
+    Ok(())
+}
+
+/// Create an API key and print it, including the only copy of its token.
+pub async fn create_api_key(settings: &Settings, name: String, scope: Scope) -> Result<(), String> {
+    let created = with_connection(settings, move |conn| {
+        auth::create_api_key(conn, auth::NewApiKey { name, scope })
+    })
+    .await?
+    .map_err(|e| format!("error writing API key to database {e}"))?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&created).map_err(|e| e.to_string())?
+    );
+    Ok(())
+}
+
+/// Parse an `--on-conflict` value.
+pub fn parse_on_conflict(s: &str) -> Result<OnConflict, String> {
+    match s {
+        "error" => Ok(OnConflict::Error),
+        "ignore" => Ok(OnConflict::Ignore),
+        "update" => Ok(OnConflict::Update),
+        _ => Err(String::from("not one of error, ignore or update")),
+    }
+}
+
+/// Parse a `--scope` value.
+pub fn parse_scope(s: &str) -> Result<Scope, String> {
+    s.parse().map_err(|e: core::ScopeError| e.to_string())
+}
+
+/// Run `f` with a connection from a single-connection pool.
+async fn with_connection<T: Send + 'static>(
+    settings: &Settings,
+    f: impl FnOnce(&mut PgConnection) -> T + Send + 'static,
+) -> Result<T, String> {
+    let conn_pool: Pool = create_database_connection_pool(&settings.database, 1)
+        .map_err(|e| format!("unable to connect to database {e}"))?;
+    let client = conn_pool
+        .get()
+        .await
+        .map_err(|e| format!("unable to get database connection {e}"))?;
+    client
+        .interact(f)
+        .await
+        .map_err(|e| format!("error interacting through connection pool {e:?}"))
+}
@@ -0,0 +1,220 @@
+use crate::replica::ReadFrom;
+use crate::storage;
+use crate::AppState;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::future::Future;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use tracing::{debug_span, error, Instrument};
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to handle HTTP requests.",
+            ),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static INTERACT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "storage_interact_duration_seconds",
+                "Time taken by storage functions run through the connection pool, including handoff.",
+            ),
+            &["function"],
+        )
+        .unwrap(),
+    )
+});
+
+static POOL_CONNECTIONS: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "db_pool_connections",
+                "Database connection pool state: max, size, available and waiting.",
+            ),
+            &["state"],
+        )
+        .unwrap(),
+    )
+});
+
+static FACILITIES: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new("facilities", "Stored facilities per segment."),
+            &["segment"],
+        )
+        .unwrap(),
+    )
+});
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "cache_lookups_total",
+                "Facility cache lookups, by cache and whether they hit or missed.",
+            ),
+            &["cache", "result"],
+        )
+        .unwrap(),
+    )
+});
+
+fn register<M: prometheus::core::Collector + Clone + 'static>(metric: M) -> M {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// Middleware counting requests and timing them per route and status.
+///
+/// Routes are labelled by their pattern, like "/v1/facilities/{uid}", to keep label values bounded.
+pub async fn track(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or(String::from("unmatched"));
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    HTTP_REQUESTS.with_label_values(&labels).inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+/// Time a storage function run through `interact`, labelled with the function's name.
+///
+/// The call also gets its own span, a child of the request's.
+pub async fn timed<F: Future>(function: &str, interaction: F) -> F::Output {
+    let start = Instant::now();
+    let output = interaction
+        .instrument(debug_span!("storage", function))
+        .await;
+    INTERACT_DURATION
+        .with_label_values(&[function])
+        .observe(start.elapsed().as_secs_f64());
+    output
+}
+
+/// Count a lookup in one of the facility caches.
+pub fn count_cache_lookup(cache: &str, hit: bool) {
+    CACHE_LOOKUPS
+        .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+        .inc();
+}
+
+/// How often stored facilities are counted for the `facilities` gauge, as counting scans the whole table.
+const FACILITY_COUNT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Keep the `facilities` gauge up to date, counting from the replica when there is one.
+pub async fn count_facilities_periodically(state: AppState) {
+    let mut interval = tokio::time::interval(FACILITY_COUNT_INTERVAL);
+    loop {
+        interval.tick().await;
+        count_facilities(&state).await;
+    }
+}
+
+async fn count_facilities(state: &AppState) {
+    let client = match state.read_connection(ReadFrom::Replica).await {
+        Ok(client) => client,
+        Err(e) => {
+            error!("error collecting client from connection pool {e:?}");
+            return;
+        }
+    };
+    match timed(
+        "count_facilities_by_segment",
+        client.interact(storage::count_facilities_by_segment),
+    )
+    .await
+    {
+        Ok(Ok(counts)) => {
+            // Forget segments that no longer have any facilities.
+            FACILITIES.reset();
+            for (segment, count) in counts {
+                FACILITIES.with_label_values(&[&segment]).set(count);
+            }
+        }
+        Ok(Err(e)) => error!("error counting facilities for metrics {e:?}"),
+        Err(e) => error!("error interacting through connection pool {e:?}"),
+    }
+}
+
+/// Handle Prometheus scrapes, refreshing the connection pool gauges first.
+///
+/// Facility counts come from the last periodic count instead, so scrapes don't touch the database.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "operations",
+    responses((status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain")),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    let status = state.conn_pool.status();
+    for (label, value) in [
+        ("max", status.max_size),
+        ("size", status.size),
+        ("available", status.available),
+        ("waiting", status.waiting),
+    ] {
+        POOL_CONNECTIONS
+            .with_label_values(&[label])
+            .set(value as i64);
+    }
+
+    // Make sure every metric is listed, even before it has been observed.
+    LazyLock::force(&HTTP_REQUESTS);
+    LazyLock::force(&HTTP_REQUEST_DURATION);
+    LazyLock::force(&INTERACT_DURATION);
+    LazyLock::force(&FACILITIES);
+    LazyLock::force(&CACHE_LOOKUPS);
+
+    let mut body = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut body) {
+        error!("error encoding metrics {e:?}");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        body,
+    )
+        .into_response()
+}
@@ -0,0 +1,73 @@
+use crate::storage;
+use diesel::sql_types::BigInt;
+use diesel::{PgConnection, RunQueryDsl};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::error::Error;
+use std::time::Duration;
+
+/// Migrations from the migrations/ directory, built into the binary.
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Advisory lock key held while migrating, so only one replica migrates at a time.
+const MIGRATION_LOCK: i64 = 0x6166_6173_7474_6f79;
+
+pub type MigrationError = Box<dyn Error + Send + Sync>;
+
+/// Names of embedded migrations not yet applied to the database.
+pub fn pending_migrations(conn: &mut PgConnection) -> Result<Vec<String>, MigrationError> {
+    Ok(conn
+        .pending_migrations(MIGRATIONS)?
+        .iter()
+        .map(|m| m.name().to_string())
+        .collect())
+}
+
+/// Apply every pending migration, returning the versions applied.
+///
+/// Holds an advisory lock throughout, so replicas starting together wait for the first to finish
+/// and then find nothing left to do.
+pub fn run_pending_migrations(conn: &mut PgConnection) -> Result<Vec<String>, MigrationError> {
+    with_migration_lock(conn, |conn| {
+        Ok(conn
+            .run_pending_migrations(MIGRATIONS)?
+            .iter()
+            .map(|v| v.to_string())
+            .collect())
+    })
+}
+
+/// Revert the most recently applied migration, returning its version.
+pub fn revert_last_migration(conn: &mut PgConnection) -> Result<String, MigrationError> {
+    with_migration_lock(conn, |conn| {
+        Ok(conn.revert_last_migration(MIGRATIONS)?.to_string())
+    })
+}
+
+/// Versions of migrations already applied to the database, oldest first.
+pub fn applied_migrations(conn: &mut PgConnection) -> Result<Vec<String>, MigrationError> {
+    let mut versions: Vec<String> = conn
+        .applied_migrations()?
+        .iter()
+        .map(|v| v.to_string())
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
+/// Run `f` holding the migration lock, with no statement timeout for the rest of the session.
+///
+/// Waiting for another replica's migrations, or building an index, can take longer than any request should.
+fn with_migration_lock<T>(
+    conn: &mut PgConnection,
+    f: impl FnOnce(&mut PgConnection) -> Result<T, MigrationError>,
+) -> Result<T, MigrationError> {
+    storage::set_statement_timeout(conn, Duration::ZERO)?;
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK)
+        .execute(conn)?;
+    let result = f(conn);
+    diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK)
+        .execute(conn)?;
+    result
+}
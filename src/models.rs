@@ -1,19 +1,19 @@
 use crate::core;
-use crate::core::FacilityError;
-use crate::schema::facilities;
-use chrono::NaiveDate;
+use crate::core::{FacilityError, ScopeError};
+use crate::schema::{api_keys, facilities};
+use chrono::{NaiveDate, NaiveDateTime};
 use diesel::prelude::*;
 use serde::Serialize;
 
-#[derive(Clone, Debug, Selectable, Insertable, Queryable, Serialize)]
-#[diesel(table_name = facilities, check_for_backend(diesel::pg::Pg))]
+#[derive(Clone, Debug, Selectable, Insertable, Queryable, AsChangeset, Serialize)]
+#[diesel(table_name = facilities, primary_key(uid), treat_none_as_null = true, check_for_backend(diesel::pg::Pg))]
 pub struct Facility {
     pub uid: String,
     pub company: String,
     pub segment: String,
     pub technology: String,
-    pub latitude: f32,
-    pub longitude: f32,
+    pub latitude: f64,
+    pub longitude: f64,
     pub announcement_date: NaiveDate,
     pub estimated_investment: Option<i64>,
 }
@@ -49,3 +49,35 @@ impl From<core::Facility> for Facility {
         }
     }
 }
+
+#[derive(Clone, Debug, Selectable, Queryable)]
+#[diesel(table_name = api_keys, check_for_backend(diesel::pg::Pg))]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: String,
+    pub secret_hash: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[diesel(table_name = api_keys, check_for_backend(diesel::pg::Pg))]
+pub struct NewApiKey {
+    pub id: String,
+    pub name: String,
+    pub scope: String,
+    pub secret_hash: String,
+}
+
+impl TryFrom<ApiKey> for core::ApiKey {
+    type Error = ScopeError;
+
+    fn try_from(value: ApiKey) -> Result<Self, Self::Error> {
+        Ok(core::ApiKey {
+            id: value.id,
+            name: value.name,
+            scope: value.scope.parse()?,
+            created_at: value.created_at,
+        })
+    }
+}
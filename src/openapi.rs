@@ -0,0 +1,108 @@
+use crate::auth::API_KEY_HEADER;
+use crate::problem::Problem;
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::openapi::{ContentBuilder, Ref, ResponseBuilder};
+use utoipa::{Modify, OpenApi};
+
+/// OpenAPI description of the API, generated from the handlers and the types they take and return.
+#[derive(OpenApi)]
+#[openapi(
+    nest((path = "/v1", api = V1ApiDoc)),
+    paths(
+        crate::health::healthz,
+        crate::health::readyz,
+        crate::metrics::metrics,
+    ),
+    modifiers(&Security, &ProblemResponses),
+    tags((name = "operations", description = "Probes and metrics for running the service.")),
+)]
+pub struct ApiDoc;
+
+/// Version 1 of the API, relative to its /v1 prefix.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        crate::post_facility,
+        crate::import_facilities,
+        crate::get_facility,
+        crate::get_facilities,
+        crate::search_facilities,
+        crate::delete_facility,
+        crate::post_api_key,
+        crate::get_api_keys,
+        crate::delete_api_key,
+    ),
+    components(schemas(Problem)),
+    tags(
+        (name = "facilities", description = "Facilities and their locations."),
+        (name = "api-keys", description = "API keys for clients. Requires admin scope."),
+    )
+)]
+struct V1ApiDoc;
+
+/// Describes the two ways clients can authenticate.
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(API_KEY_HEADER))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// Adds the problem responses every authenticated operation can give, rather than repeating them on each handler.
+struct ProblemResponses;
+
+impl Modify for ProblemResponses {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for item in openapi.paths.paths.values_mut() {
+            for operation in [&mut item.get, &mut item.post, &mut item.delete]
+                .into_iter()
+                .flatten()
+                .filter(|o| o.security.is_some())
+            {
+                add_problem_response(operation, "401", "Missing or invalid credentials");
+                add_problem_response(operation, "403", "Credentials lack the required scope");
+                if operation.request_body.is_some() {
+                    add_problem_response(operation, "413", "Request body too large");
+                }
+                add_problem_response(operation, "429", "Rate limit exceeded");
+                add_problem_response(operation, "500", "Unexpected server error");
+                add_problem_response(operation, "503", "No database connection came free in time");
+                add_problem_response(operation, "504", "Request took too long");
+            }
+        }
+    }
+}
+
+fn add_problem_response(operation: &mut Operation, status: &str, description: &str) {
+    operation
+        .responses
+        .responses
+        .entry(String::from(status))
+        .or_insert_with(|| {
+            ResponseBuilder::new()
+                .description(description)
+                .content(
+                    "application/problem+json",
+                    ContentBuilder::new()
+                        .schema(Some(Ref::from_schema_name("Problem")))
+                        .build(),
+                )
+                .build()
+                .into()
+        });
+}
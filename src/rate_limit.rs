@@ -0,0 +1,499 @@
+use crate::auth::Principal;
+use crate::cache::Cache;
+use crate::metrics;
+use crate::problem::Problem;
+use crate::storage;
+use crate::versions;
+use crate::AppState;
+use axum::extract::{ConnectInfo, MatchedPath, Request, State};
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use deadpool_diesel::postgres::Pool;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// Most buckets an in-process store keeps, dropping the least recently used beyond it.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+
+/// Token bucket sizing, written as "capacity/seconds", e.g. "100/60" for bursts of 100 requests
+/// refilling at 100 requests per minute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Policy {
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.capacity) / self.period.as_secs_f64()
+    }
+
+    /// Seconds until a bucket with `tokens` left holds `target` tokens again.
+    fn seconds_until(&self, tokens: f64, target: f64) -> u64 {
+        ((target - tokens).max(0.0) / self.refill_per_second()).ceil() as u64
+    }
+}
+
+impl FromStr for Policy {
+    type Err = PolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (capacity, seconds) = s.trim().split_once('/').ok_or(PolicyError)?;
+        let capacity: u32 = capacity.trim().parse().map_err(|_| PolicyError)?;
+        let seconds: u64 = seconds.trim().parse().map_err(|_| PolicyError)?;
+        if capacity == 0 || seconds == 0 {
+            return Err(PolicyError);
+        }
+        Ok(Policy {
+            capacity,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PolicyError;
+
+impl std::fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "not a positive \"capacity/seconds\" rate limit")
+    }
+}
+
+/// How often shared buckets that have filled up again are deleted.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rate limits for every route, with optional overrides for some.
+pub struct RateLimits {
+    pub default: Policy,
+    /// Policies for routes keyed by "METHOD /route/{param}".
+    pub routes: HashMap<String, Policy>,
+    pub store: Store,
+    pub auth_failures: AuthFailures,
+}
+
+impl RateLimits {
+    fn policy_for(&self, route: &str) -> Policy {
+        self.routes.get(route).copied().unwrap_or(self.default)
+    }
+
+    /// Longest period of any policy, after which every bucket has filled up again.
+    fn longest_period(&self) -> Duration {
+        self.routes
+            .values()
+            .map(|p| p.period)
+            .fold(self.default.period, Duration::max)
+    }
+}
+
+/// Parse route overrides like "GET /facilities=60/60;POST /facility-imports=5/60".
+pub fn parse_route_policies(s: &str) -> Result<HashMap<String, Policy>, PolicyError> {
+    s.split(';')
+        .filter(|r| !r.trim().is_empty())
+        .map(|r| {
+            let (route, policy) = r.rsplit_once('=').ok_or(PolicyError)?;
+            Ok((route.trim().to_string(), policy.parse()?))
+        })
+        .collect()
+}
+
+/// Route key as requests are matched, with no trailing slash, so "GET /facilities/" is "GET /facilities".
+pub fn normalize_route(route: &str) -> String {
+    match route.split_once(' ') {
+        Some((method, path)) if path.len() > 1 => {
+            format!("{method} {}", path.trim_end_matches('/'))
+        }
+        _ => route.to_string(),
+    }
+}
+
+/// Where token buckets are kept.
+pub enum Store {
+    /// Buckets in this process only, so each replica limits separately.
+    ///
+    /// Buckets are forgotten once full, as they then behave exactly like missing ones. Beyond `MAX_MEMORY_BUCKETS`
+    /// the least recently used go early, which lets their clients start over.
+    Memory(Cache<String, Bucket>),
+    /// Buckets in Postgres, shared by every replica using the same database.
+    ///
+    /// Denials are remembered in this process until the bucket has a token again, so clients over their limit
+    /// don't cost a round trip each.
+    Postgres {
+        pool: Pool,
+        denied: Cache<String, Bucket>,
+    },
+}
+
+impl Store {
+    pub fn memory() -> Self {
+        // Buckets say when they expire themselves, once they fill up again, so no TTL applies.
+        Store::Memory(Cache::new(MAX_MEMORY_BUCKETS, Duration::ZERO))
+    }
+
+    pub fn postgres(pool: Pool) -> Self {
+        Store::Postgres {
+            pool,
+            denied: Cache::new(MAX_MEMORY_BUCKETS, Duration::ZERO),
+        }
+    }
+
+    /// The store's own connection pool, if it has one.
+    pub fn pool(&self) -> Option<&Pool> {
+        match self {
+            Store::Memory(_) => None,
+            Store::Postgres { pool, .. } => Some(pool),
+        }
+    }
+}
+
+/// Token bucket, sized by the policy it was made for.
+#[derive(Clone)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    capacity: f64,
+    refill_per_second: f64,
+}
+
+impl Bucket {
+    fn full(policy: Policy, now: Instant) -> Self {
+        Bucket {
+            tokens: f64::from(policy.capacity),
+            updated: now,
+            capacity: f64::from(policy.capacity),
+            refill_per_second: policy.refill_per_second(),
+        }
+    }
+
+    /// Tokens in the bucket at some time, counting what it refilled since it was last used.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.refill_per_second).min(self.capacity)
+    }
+
+    /// When the bucket holds a number of tokens again.
+    fn holds_at(&self, tokens: f64) -> Instant {
+        let seconds = (tokens - self.tokens).max(0.0) / self.refill_per_second;
+        self.updated + Duration::try_from_secs_f64(seconds).unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Outcome of trying to take a token from a bucket.
+struct Decision {
+    allowed: bool,
+    remaining: f64,
+}
+
+impl Store {
+    async fn take(&self, key: String, policy: Policy) -> Result<Decision, String> {
+        match self {
+            Store::Memory(buckets) => Ok(take_from_memory(buckets, key, policy, Instant::now())),
+            Store::Postgres { pool, denied } => {
+                let now = Instant::now();
+                if let Some(bucket) = denied.get(&key, now) {
+                    return Ok(Decision {
+                        allowed: false,
+                        remaining: bucket.tokens_at(now),
+                    });
+                }
+
+                let capacity = f64::from(policy.capacity);
+                let refill_per_second = policy.refill_per_second();
+                let client = pool.get().await.map_err(|e| e.to_string())?;
+                let shared_key = key.clone();
+                let (allowed, remaining) = metrics::timed(
+                    "take_rate_limit_token",
+                    client.interact(move |conn| {
+                        storage::take_rate_limit_token(
+                            conn,
+                            shared_key,
+                            capacity,
+                            refill_per_second,
+                        )
+                    }),
+                )
+                .await
+                .map_err(|e| e.to_string())?
+                .map_err(|e| e.to_string())?;
+                if !allowed {
+                    let bucket = Bucket {
+                        tokens: remaining,
+                        ..Bucket::full(policy, now)
+                    };
+                    let retry_at = bucket.holds_at(1.0);
+                    denied.update(key, now, |_| (bucket, retry_at, ()));
+                }
+                Ok(Decision { allowed, remaining })
+            }
+        }
+    }
+}
+
+fn take_from_memory(
+    buckets: &Cache<String, Bucket>,
+    key: String,
+    policy: Policy,
+    now: Instant,
+) -> Decision {
+    buckets.update(key, now, |bucket| {
+        let mut bucket = bucket.unwrap_or_else(|| Bucket::full(policy, now));
+        bucket.tokens = bucket.tokens_at(now);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let decision = Decision {
+            allowed,
+            remaining: bucket.tokens,
+        };
+        let full_at = bucket.holds_at(bucket.capacity);
+        (bucket, full_at, decision)
+    })
+}
+
+/// Failed authentications by IP address, counted in this process whatever the store.
+///
+/// Authentication runs before the routes' limits, so this is what stops guessed credentials from
+/// each being looked up in storage.
+pub struct AuthFailures {
+    pub policy: Policy,
+    buckets: Cache<String, Bucket>,
+}
+
+impl AuthFailures {
+    pub fn new(policy: Policy) -> Self {
+        AuthFailures {
+            policy,
+            buckets: Cache::new(MAX_MEMORY_BUCKETS, Duration::ZERO),
+        }
+    }
+
+    /// Seconds an address has to wait before trying to authenticate again, if it failed too often.
+    pub fn retry_after(&self, ip: IpAddr) -> Option<u64> {
+        let now = Instant::now();
+        let bucket = self.buckets.get(&ip.to_string(), now)?;
+        (bucket.tokens_at(now) < 1.0).then(|| {
+            bucket
+                .holds_at(1.0)
+                .saturating_duration_since(now)
+                .as_secs()
+                .max(1)
+        })
+    }
+
+    /// Count a failed authentication against an address.
+    pub fn record(&self, ip: IpAddr) {
+        take_from_memory(&self.buckets, ip.to_string(), self.policy, Instant::now());
+    }
+
+    /// Response for an address that has to wait before trying again.
+    pub fn too_many(&self, retry_after: u64) -> Response {
+        let mut response = Problem::new(StatusCode::TOO_MANY_REQUESTS)
+            .with_detail(format!(
+                "more than {} failed authentications per {} seconds",
+                self.policy.capacity,
+                self.policy.period.as_secs()
+            ))
+            .into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+/// Delete shared buckets every so often once they have filled up again, as they then behave just like missing ones.
+pub async fn delete_full_buckets_periodically(rate_limits: Arc<RateLimits>) {
+    let Store::Postgres { pool, .. } = &rate_limits.store else {
+        return;
+    };
+    let idle = rate_limits.longest_period();
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let deleted = match pool.get().await {
+            Ok(client) => client
+                .interact(move |conn| storage::delete_idle_rate_limit_buckets(conn, idle))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|r| r.map_err(|e| e.to_string())),
+            Err(e) => Err(e.to_string()),
+        };
+        match deleted {
+            Ok(n) => debug!("deleted {n} full rate limit buckets"),
+            Err(e) => error!("error deleting full rate limit buckets {e}"),
+        }
+    }
+}
+
+/// Middleware limiting each client's request rate to each route.
+///
+/// Clients are identified by their API key or token subject when authenticated and by IP address
+/// otherwise. Responses carry RateLimit-* headers and rejected requests get 429 with Retry-After.
+pub async fn limit(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(rate_limits) = &state.rate_limits else {
+        return next.run(request).await;
+    };
+
+    // Every version of a route, and its unversioned alias, share one limit.
+    let route = format!(
+        "{} {}",
+        request.method(),
+        request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(|p| versions::unversioned(p.as_str()))
+            .unwrap_or("unmatched")
+    );
+    let client = match request.extensions().get::<Principal>() {
+        Some(principal) => format!("subject:{}", principal.subject),
+        None => match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => format!("ip:{}", addr.ip()),
+            None => String::from("ip:unknown"),
+        },
+    };
+    let policy = rate_limits.policy_for(&route);
+
+    let decision = match rate_limits
+        .store
+        .take(format!("{client} {route}"), policy)
+        .await
+    {
+        Ok(d) => d,
+        Err(e) => {
+            // Don't take the API down with the rate limit store.
+            error!("error checking rate limit, letting request through {e}");
+            return next.run(request).await;
+        }
+    };
+
+    if !decision.allowed {
+        debug!("rate limited {client} on {route}");
+        let mut response = Problem::new(StatusCode::TOO_MANY_REQUESTS)
+            .with_detail(format!(
+                "rate limit of {} requests per {} seconds exceeded",
+                policy.capacity,
+                policy.period.as_secs()
+            ))
+            .into_response();
+        let retry_after = policy.seconds_until(decision.remaining, 1.0).max(1);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        insert_rate_limit_headers(response.headers_mut(), policy, decision.remaining);
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    insert_rate_limit_headers(response.headers_mut(), policy, decision.remaining);
+    response
+}
+
+fn insert_rate_limit_headers(headers: &mut HeaderMap, policy: Policy, remaining: f64) {
+    let capacity = f64::from(policy.capacity);
+    headers.insert(
+        HeaderName::from_static("ratelimit-limit"),
+        HeaderValue::from(policy.capacity),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-remaining"),
+        HeaderValue::from(remaining.floor() as u64),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-reset"),
+        HeaderValue::from(policy.seconds_until(remaining, capacity)),
+    );
+    headers.insert(
+        HeaderName::from_static("ratelimit-policy"),
+        HeaderValue::from_str(&format!(
+            "{};w={}",
+            policy.capacity,
+            policy.period.as_secs()
+        ))
+        .unwrap(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!(
+            "100/60".parse(),
+            Ok(Policy {
+                capacity: 100,
+                period: Duration::from_secs(60)
+            })
+        );
+        assert_eq!("100".parse::<Policy>(), Err(PolicyError));
+        assert_eq!("0/60".parse::<Policy>(), Err(PolicyError));
+
+        let routes =
+            parse_route_policies("GET /facilities/=60/60; POST /facility-imports=5/60").unwrap();
+        assert_eq!(routes["POST /facility-imports"].capacity, 5);
+
+        assert_eq!(normalize_route("GET /facilities/"), "GET /facilities");
+        assert_eq!(normalize_route("GET /"), "GET /");
+    }
+
+    #[test]
+    fn memory_bucket_empties_and_refills() {
+        let buckets = Cache::new(MAX_MEMORY_BUCKETS, Duration::ZERO);
+        let policy: Policy = "2/10".parse().unwrap();
+        let start = Instant::now();
+        let key = || String::from("client");
+
+        assert!(take_from_memory(&buckets, key(), policy, start).allowed);
+        assert!(take_from_memory(&buckets, key(), policy, start).allowed);
+        let denied = take_from_memory(&buckets, key(), policy, start);
+        assert!(!denied.allowed);
+        assert_eq!(policy.seconds_until(denied.remaining, 1.0), 5);
+
+        let later = start + Duration::from_secs(5);
+        assert!(take_from_memory(&buckets, key(), policy, later).allowed);
+    }
+
+    #[test]
+    fn memory_buckets_keep_their_own_policy_and_stay_bounded() {
+        let buckets = Cache::new(2, Duration::ZERO);
+        let start = Instant::now();
+        let later = start + Duration::from_secs(1);
+        let strict: Policy = "1/100".parse().unwrap();
+        let loose: Policy = "100/1".parse().unwrap();
+        let take =
+            |key: &str, policy, now| take_from_memory(&buckets, key.to_string(), policy, now);
+
+        assert!(take("strict", strict, start).allowed);
+        take("loose", loose, start);
+        // Taking from a bucket with a faster refill doesn't refill or forget this one.
+        take("loose", loose, later);
+        assert!(!take("strict", strict, later).allowed);
+
+        // A third client pushes out the least recently used bucket, whose client starts over.
+        take("loose", loose, later);
+        take("other", loose, later);
+        assert!(take("strict", strict, later).allowed);
+    }
+
+    #[test]
+    fn failed_authentications_make_addresses_wait() {
+        let failures = AuthFailures::new("2/60".parse().unwrap());
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(failures.retry_after(ip), None);
+        failures.record(ip);
+        assert_eq!(failures.retry_after(ip), None);
+        failures.record(ip);
+        let retry_after = failures.retry_after(ip).unwrap();
+        assert!((29..=30).contains(&retry_after));
+        assert_eq!(failures.retry_after("192.0.2.2".parse().unwrap()), None);
+    }
+}
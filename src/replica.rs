@@ -0,0 +1,116 @@
+use crate::AppState;
+use axum::extract::{FromRequestParts, Request, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use deadpool_diesel::postgres::Pool;
+use std::convert::Infallible;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cookie holding when the client last wrote, in seconds since the Unix epoch.
+pub const LAST_WRITE_COOKIE: &str = "last_write";
+
+/// Read replica, and how long after writing a client reads from the primary instead.
+#[derive(Clone)]
+pub struct Replica {
+    pub pool: Pool,
+    pub read_your_writes: Duration,
+}
+
+/// Which database a read should go to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadFrom {
+    /// The client wrote recently, and the replica or any cache may not have its writes yet.
+    Primary,
+    /// The read may lag a little behind writes, so the replica will do, or the primary without one.
+    Replica,
+}
+
+impl FromRequestParts<AppState> for ReadFrom {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        Ok(match &state.replica {
+            Some(replica) => read_from(&parts.headers, replica.read_your_writes, now()),
+            None => ReadFrom::Replica,
+        })
+    }
+}
+
+fn read_from(headers: &HeaderMap, read_your_writes: Duration, now: u64) -> ReadFrom {
+    let wrote_recently = last_write(headers)
+        // Either way, as other instances' clocks may be a little ahead.
+        .is_some_and(|last_write| last_write.abs_diff(now) < read_your_writes.as_secs());
+    if wrote_recently {
+        ReadFrom::Primary
+    } else {
+        ReadFrom::Replica
+    }
+}
+
+fn last_write(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == LAST_WRITE_COOKIE)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Middleware setting the last write cookie on successful writes, so the client's next reads see them.
+pub async fn remember_writes(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let mut response = next.run(request).await;
+    if let (Some(replica), true) = (&state.replica, response.status().is_success()) {
+        let cookie = format!(
+            "{LAST_WRITE_COOKIE}={}; Max-Age={}; Path=/; HttpOnly; SameSite=Lax",
+            now(),
+            replica.read_your_writes.as_secs()
+        );
+        if let Ok(cookie) = HeaderValue::try_from(cookie) {
+            response.headers_mut().append(header::SET_COOKIE, cookie);
+        }
+    }
+    response
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_from_primary_shortly_after_writing() {
+        let window = Duration::from_secs(5);
+        let headers = |cookies: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::COOKIE, HeaderValue::from_str(cookies).unwrap());
+            headers
+        };
+
+        let recent = headers("theme=dark; last_write=1000");
+        assert_eq!(read_from(&recent, window, 1003), ReadFrom::Primary);
+        assert_eq!(read_from(&recent, window, 998), ReadFrom::Primary);
+        assert_eq!(read_from(&recent, window, 1005), ReadFrom::Replica);
+        assert_eq!(
+            read_from(&HeaderMap::new(), window, 1000),
+            ReadFrom::Replica
+        );
+        assert_eq!(
+            read_from(&headers("last_write=soon"), window, 1000),
+            ReadFrom::Replica
+        );
+    }
+}
@@ -0,0 +1,89 @@
+use crate::problem::Problem;
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::{header, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::error;
+
+/// Header carrying the request's ID, both ways.
+pub static REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied request ID we accept.
+const MAX_REQUEST_ID_LENGTH: usize = 200;
+
+/// Largest error body we'll open up to add a request ID to.
+const MAX_PROBLEM_BODY_BYTES: usize = 64 * 1024;
+
+/// Middleware giving every request an ID, echoed in the response and any problem body.
+///
+/// Clients and gateways can send their own X-Request-Id. Otherwise, or if it isn't short printable ASCII,
+/// a new UUID is used. Request spans pick the ID up from the request headers.
+pub async fn propagate(mut request: Request, next: Next) -> Response {
+    let request_id = match request.headers().get(&REQUEST_ID_HEADER) {
+        Some(id) if is_acceptable(id) => id.clone(),
+        _ => {
+            let id = HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).unwrap();
+            request
+                .headers_mut()
+                .insert(REQUEST_ID_HEADER.clone(), id.clone());
+            id
+        }
+    };
+
+    let mut response = next.run(request).await;
+    if response.status().is_client_error() || response.status().is_server_error() {
+        response = with_problem_request_id(response, request_id.to_str().unwrap_or_default()).await;
+    }
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER.clone(), request_id);
+    response
+}
+
+fn is_acceptable(id: &HeaderValue) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.as_bytes().iter().all(|b| b.is_ascii_graphic())
+}
+
+/// Add a `request_id` member to an error response's problem body, giving bare errors one first.
+async fn with_problem_request_id(response: Response, request_id: &str) -> Response {
+    let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_PROBLEM_BODY_BYTES).await else {
+        error!("error reading error response body to add request ID");
+        return Problem::new(parts.status).into_response();
+    };
+
+    let mut problem = if bytes.is_empty() {
+        let Ok(problem) = serde_json::to_value(Problem::new(parts.status)) else {
+            return Response::from_parts(parts, Body::from(bytes));
+        };
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        problem
+    } else if content_type.as_ref().and_then(|v| v.to_str().ok())
+        == Some("application/problem+json")
+    {
+        match serde_json::from_slice(&bytes) {
+            Ok(problem) => problem,
+            Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+        }
+    } else {
+        // Some other kind of error body, like axum's plain text extractor rejections.
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+
+    if let Some(members) = problem.as_object_mut() {
+        members.insert(
+            String::from("request_id"),
+            serde_json::Value::from(request_id),
+        );
+    }
+    let body = serde_json::to_vec(&problem).unwrap_or_default();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
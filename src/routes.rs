@@ -0,0 +1,66 @@
+use crate::problem::Problem;
+use axum::extract::Request;
+use axum::http::uri::PathAndQuery;
+use axum::http::{Method, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Path of the Swagger UI, whose relative links to its assets rely on its trailing slash.
+pub const DOCS_PATH: &str = "/docs";
+
+/// Middleware serving paths with trailing slashes as if they had none, so "/v1/facilities/" is "/v1/facilities".
+///
+/// This must wrap the Router rather than be one of its layers, which only run after routing.
+pub async fn trim_trailing_slash(mut request: Request, next: Next) -> Response {
+    if let Some(uri) = trimmed(request.uri()) {
+        *request.uri_mut() = uri;
+    }
+    next.run(request).await
+}
+
+fn trimmed(uri: &Uri) -> Option<Uri> {
+    let path = uri.path();
+    if path == "/" || !path.ends_with('/') || path.starts_with(DOCS_PATH) {
+        return None;
+    }
+    let path = match path.trim_end_matches('/') {
+        "" => "/",
+        path => path,
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+/// Answer requests using a method a route doesn't support. The router adds the Allow header.
+pub async fn method_not_allowed(method: Method, uri: Uri) -> Problem {
+    Problem::new(StatusCode::METHOD_NOT_ALLOWED).with_detail(format!(
+        "{method} is not supported on {}, see the Allow header for what is",
+        uri.path()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trims_trailing_slashes() {
+        let trim = |uri: &str| trimmed(&uri.parse().unwrap()).map(|u| u.to_string());
+        assert_eq!(
+            trim("/v1/facilities/"),
+            Some(String::from("/v1/facilities"))
+        );
+        assert_eq!(
+            trim("/v1/facilities//?segment=a/b"),
+            Some(String::from("/v1/facilities?segment=a/b"))
+        );
+        assert_eq!(trim("/v1/facilities"), None);
+        assert_eq!(trim("/"), None);
+        assert_eq!(trim("/docs/"), None);
+    }
+}
@@ -1,14 +1,26 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_keys (id) {
+        id -> Text,
+        name -> Text,
+        scope -> Text,
+        secret_hash -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     facilities (uid) {
         uid -> Text,
         company -> Text,
         segment -> Text,
         technology -> Text,
-        latitude -> Float4,
-        longitude -> Float4,
+        latitude -> Float8,
+        longitude -> Float8,
         announcement_date -> Date,
         estimated_investment -> Nullable<Int8>,
     }
 }
+
+diesel::allow_tables_to_appear_in_same_query!(api_keys, facilities,);
@@ -0,0 +1,1144 @@
+use crate::body_limit;
+use crate::core::MAX_COORDINATE_PRECISION;
+use crate::rate_limit::{self, Policy};
+use axum::http::{HeaderName, HeaderValue, Method};
+use clap::Args;
+use jsonwebtoken::Algorithm;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings overridable from the command line or environment variables.
+///
+/// Command line flags win over environment variables, which win over the settings file.
+#[derive(Args, Debug, Default)]
+pub struct SettingsArgs {
+    /// TOML settings file.
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    #[arg(long, global = true, env = "HOST")]
+    pub host: Option<String>,
+    #[arg(long, global = true, env = "PORT")]
+    pub port: Option<u16>,
+    /// Port to serve /metrics on without credentials, instead of on PORT to admins.
+    #[arg(long, global = true, env = "METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+    /// Seconds to keep accepting requests after SIGTERM or SIGINT while reporting unready.
+    #[arg(long, global = true, env = "SHUTDOWN_DELAY_SECONDS")]
+    pub shutdown_delay_seconds: Option<u64>,
+    /// Seconds to let in-flight requests finish after SIGTERM or SIGINT.
+    #[arg(long, global = true, env = "SHUTDOWN_TIMEOUT_SECONDS")]
+    pub shutdown_timeout_seconds: Option<u64>,
+    /// Seconds a request may take before getting 504 Gateway Timeout.
+    #[arg(long, global = true, env = "REQUEST_TIMEOUT_SECONDS")]
+    pub request_timeout_seconds: Option<u64>,
+    /// PEM certificate chain to serve HTTPS with.
+    #[arg(long, global = true, env = "TLS_CERT_FILE")]
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM private key for the certificate.
+    #[arg(long, global = true, env = "TLS_KEY_FILE")]
+    pub tls_key_file: Option<PathBuf>,
+    /// PEM CA bundle to verify client certificates against.
+    #[arg(long, global = true, env = "TLS_CLIENT_CA_FILE")]
+    pub tls_client_ca_file: Option<PathBuf>,
+    /// Whether clients must present a certificate: "required" or "optional".
+    #[arg(long, global = true, env = "TLS_CLIENT_AUTH")]
+    pub tls_client_auth: Option<String>,
+    /// Seconds between checks of the TLS files for changes.
+    #[arg(long, global = true, env = "TLS_RELOAD_SECONDS")]
+    pub tls_reload_seconds: Option<u64>,
+    #[arg(long, global = true, env = "DATABASE_URL", hide_env_values = true)]
+    pub database_url: Option<String>,
+    /// Read replica to send reads to, like "postgresql://username@replica:5432/databasename".
+    #[arg(
+        long,
+        global = true,
+        env = "DATABASE_REPLICA_URL",
+        hide_env_values = true
+    )]
+    pub database_replica_url: Option<String>,
+    /// Seconds after writing that a client's reads go to the primary instead of the replica.
+    #[arg(long, global = true, env = "DATABASE_READ_YOUR_WRITES_SECONDS")]
+    pub database_read_your_writes_seconds: Option<u64>,
+    #[arg(long, global = true, env = "DATABASE_POOL_SIZE")]
+    pub database_pool_size: Option<usize>,
+    /// Seconds to wait for a free pooled connection.
+    #[arg(long, global = true, env = "DATABASE_POOL_TIMEOUT_SECONDS")]
+    pub database_pool_timeout_seconds: Option<u64>,
+    /// Seconds to wait for a new database connection to open.
+    #[arg(long, global = true, env = "DATABASE_CONNECT_TIMEOUT_SECONDS")]
+    pub database_connect_timeout_seconds: Option<u64>,
+    /// Seconds a database statement may run before Postgres cancels it, 0 for no limit.
+    #[arg(long, global = true, env = "DATABASE_STATEMENT_TIMEOUT_SECONDS")]
+    pub database_statement_timeout_seconds: Option<u64>,
+    /// What to do with pending migrations at startup: "apply", "check" or "skip".
+    #[arg(long, global = true, env = "MIGRATIONS")]
+    pub migrations: Option<String>,
+    /// "text" or "json".
+    #[arg(long, global = true, env = "LOG_FORMAT")]
+    pub log_format: Option<String>,
+    /// Log filter directives, like "info" or "afasttoywebapi=debug,tower_http=info".
+    #[arg(long, global = true, env = "RUST_LOG")]
+    pub log_filter: Option<String>,
+    /// OTLP/HTTP collector to export spans to, like "http://localhost:4318".
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// Service name to export spans under.
+    #[arg(long, global = true, env = "OTEL_SERVICE_NAME")]
+    pub otel_service_name: Option<String>,
+    /// Decimal places to round coordinates to in responses.
+    #[arg(long, global = true, env = "COORDINATE_PRECISION")]
+    pub coordinate_precision: Option<u32>,
+    /// Use the PostGIS location column for spatial filters when the database has it.
+    #[arg(long, global = true, env = "USE_POSTGIS")]
+    pub use_postgis: Option<bool>,
+    /// Bootstrap admin API key.
+    #[arg(long, global = true, env = "ADMIN_API_KEY", hide_env_values = true)]
+    pub admin_api_key: Option<String>,
+    #[arg(long, global = true, env = "JWT_ISSUER")]
+    pub jwt_issuer: Option<String>,
+    #[arg(long, global = true, env = "JWT_AUDIENCE")]
+    pub jwt_audience: Option<String>,
+    #[arg(long, global = true, env = "JWT_ROLES_CLAIM")]
+    pub jwt_roles_claim: Option<String>,
+    #[arg(long, global = true, env = "JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+    #[arg(long, global = true, env = "JWT_PUBLIC_KEY_FILE")]
+    pub jwt_public_key_file: Option<PathBuf>,
+    #[arg(long, global = true, env = "JWT_ALGORITHM")]
+    pub jwt_algorithm: Option<String>,
+    /// JWKS file path or http(s) URL.
+    #[arg(long, global = true, env = "JWT_JWKS")]
+    pub jwt_jwks: Option<String>,
+    #[arg(long, global = true, env = "JWT_JWKS_REFRESH_SECONDS")]
+    pub jwt_jwks_refresh_seconds: Option<u64>,
+    /// Default per-client rate limit as "capacity/seconds".
+    #[arg(long, global = true, env = "RATE_LIMIT")]
+    pub rate_limit: Option<String>,
+    /// Per-route rate limits as "METHOD /route=capacity/seconds;...".
+    #[arg(long, global = true, env = "RATE_LIMIT_ROUTES")]
+    pub rate_limit_routes: Option<String>,
+    /// "memory" or "postgres".
+    #[arg(long, global = true, env = "RATE_LIMIT_STORE")]
+    pub rate_limit_store: Option<String>,
+    /// Failed authentications allowed per IP address as "capacity/seconds".
+    #[arg(long, global = true, env = "RATE_LIMIT_AUTH_FAILURES")]
+    pub rate_limit_auth_failures: Option<String>,
+    /// Largest request body to accept, after decompression, like "2MiB".
+    #[arg(long, global = true, env = "BODY_LIMIT")]
+    pub body_limit: Option<String>,
+    /// Per-route body limits as "METHOD /route=size;...".
+    #[arg(long, global = true, env = "BODY_LIMIT_ROUTES")]
+    pub body_limit_routes: Option<String>,
+    /// Comma-separated origins browsers may call from, like "https://map.example.com", or "*" for any.
+    #[arg(long, global = true, env = "CORS_ALLOWED_ORIGINS")]
+    pub cors_allowed_origins: Option<String>,
+    /// Comma-separated methods cross-origin requests may use.
+    #[arg(long, global = true, env = "CORS_ALLOWED_METHODS")]
+    pub cors_allowed_methods: Option<String>,
+    /// Comma-separated headers cross-origin requests may send.
+    #[arg(long, global = true, env = "CORS_ALLOWED_HEADERS")]
+    pub cors_allowed_headers: Option<String>,
+    /// Let cross-origin requests carry cookies and other browser-managed credentials.
+    #[arg(long, global = true, env = "CORS_ALLOW_CREDENTIALS")]
+    pub cors_allow_credentials: Option<bool>,
+    /// Seconds browsers may cache preflight responses for.
+    #[arg(long, global = true, env = "CORS_MAX_AGE_SECONDS")]
+    pub cors_max_age_seconds: Option<u64>,
+    /// Seconds to cache facility reads for, 0 (the default) to not cache them.
+    #[arg(long, global = true, env = "CACHE_TTL_SECONDS")]
+    pub cache_ttl_seconds: Option<u64>,
+    /// Most facilities to keep cached.
+    #[arg(long, global = true, env = "CACHE_MAX_FACILITIES")]
+    pub cache_max_facilities: Option<usize>,
+    /// Most facility lists to keep cached.
+    #[arg(long, global = true, env = "CACHE_MAX_LISTS")]
+    pub cache_max_lists: Option<usize>,
+}
+
+/// Contents of a TOML settings file. Everything is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileSettings {
+    pub coordinate_precision: Option<u32>,
+    pub use_postgis: Option<bool>,
+    pub server: FileServerSettings,
+    pub database: FileDatabaseSettings,
+    pub log: FileLogSettings,
+    pub telemetry: FileTelemetrySettings,
+    pub auth: FileAuthSettings,
+    pub rate_limit: FileRateLimitSettings,
+    pub body_limit: FileBodyLimitSettings,
+    pub cors: FileCorsSettings,
+    pub cache: FileCacheSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileServerSettings {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub metrics_port: Option<u16>,
+    pub shutdown_delay_seconds: Option<u64>,
+    pub shutdown_timeout_seconds: Option<u64>,
+    pub request_timeout_seconds: Option<u64>,
+    pub tls: FileTlsSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileTlsSettings {
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    pub client_ca_file: Option<PathBuf>,
+    pub client_auth: Option<String>,
+    pub reload_seconds: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileDatabaseSettings {
+    pub url: Option<String>,
+    pub replica_url: Option<String>,
+    pub read_your_writes_seconds: Option<u64>,
+    pub pool_size: Option<usize>,
+    pub pool_timeout_seconds: Option<u64>,
+    pub connect_timeout_seconds: Option<u64>,
+    pub statement_timeout_seconds: Option<u64>,
+    pub migrations: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileLogSettings {
+    pub format: Option<String>,
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileTelemetrySettings {
+    pub otlp_endpoint: Option<String>,
+    pub service_name: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileAuthSettings {
+    pub admin_api_key: Option<String>,
+    pub jwt: FileJwtSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileJwtSettings {
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub roles_claim: Option<String>,
+    pub secret: Option<String>,
+    pub public_key_file: Option<PathBuf>,
+    pub algorithm: Option<String>,
+    pub jwks: Option<String>,
+    pub jwks_refresh_seconds: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileRateLimitSettings {
+    pub default: Option<String>,
+    pub store: Option<String>,
+    pub auth_failures: Option<String>,
+    /// Policies keyed by "METHOD /route".
+    pub routes: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileBodyLimitSettings {
+    pub default: Option<String>,
+    /// Sizes keyed by "METHOD /route".
+    pub routes: HashMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileCorsSettings {
+    pub allowed_origins: Option<Vec<String>>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_seconds: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileCacheSettings {
+    pub ttl_seconds: Option<u64>,
+    pub max_facilities: Option<usize>,
+    pub max_lists: Option<usize>,
+}
+
+/// Validated settings for the whole application.
+#[derive(Debug)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub database: DatabaseSettings,
+    pub log: LogSettings,
+    pub telemetry: Option<TelemetrySettings>,
+    pub coordinate_precision: Option<u32>,
+    pub use_postgis: bool,
+    pub admin_api_key: Option<String>,
+    pub jwt: Option<JwtSettings>,
+    pub rate_limit: Option<RateLimitSettings>,
+    pub body_limit: BodyLimitSettings,
+    /// Cross-origin access for browsers, closed unless some origins are allowed.
+    pub cors: Option<CorsSettings>,
+    /// Caching of facility reads, unless turned off.
+    pub cache: Option<CacheSettings>,
+}
+
+#[derive(Debug)]
+pub struct ServerSettings {
+    pub host: String,
+    pub port: u16,
+    /// Serve /metrics on its own port without credentials, if set.
+    pub metrics_port: Option<u16>,
+    /// How long to report unready before refusing new connections when shutting down, so load balancers notice first.
+    pub shutdown_delay: Duration,
+    /// How long to wait for in-flight requests when shutting down.
+    pub shutdown_timeout: Duration,
+    /// How long a request may take before it's answered with 504 Gateway Timeout.
+    pub request_timeout: Duration,
+    /// Serve HTTPS instead of HTTP, if set.
+    pub tls: Option<TlsSettings>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    /// Verify client certificates against this CA bundle, if set.
+    pub client_ca_file: Option<PathBuf>,
+    /// Turn away clients without a certificate, when verifying them.
+    pub client_auth_required: bool,
+    /// How often to check the files for changes.
+    pub reload_interval: Duration,
+}
+
+impl ServerSettings {
+    /// Address to listen on, as "host:port".
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    /// Address to serve /metrics on, as "host:port", if it has its own port.
+    pub fn metrics_address(&self) -> Option<String> {
+        self.metrics_port
+            .map(|port| format!("{}:{}", self.host, port))
+    }
+}
+
+#[derive(Debug)]
+pub struct DatabaseSettings {
+    pub url: String,
+    /// Read replica for reads that can lag a little behind writes, if any.
+    pub replica_url: Option<String>,
+    /// How long after writing a client reads from the primary, to see its own writes.
+    pub read_your_writes: Duration,
+    pub pool_size: usize,
+    pub pool_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// How long Postgres lets each statement run, if limited.
+    pub statement_timeout: Option<Duration>,
+    pub migrations: MigrationMode,
+}
+
+/// What to do at startup with migrations the database hasn't had yet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationMode {
+    /// Apply them.
+    Apply,
+    /// Refuse to start.
+    Check,
+    /// Leave them for someone else to apply.
+    Skip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    pub filter: String,
+}
+
+/// Where to export trace spans to.
+#[derive(Debug)]
+pub struct TelemetrySettings {
+    /// OTLP/HTTP traces URL, ending in "/v1/traces".
+    pub otlp_endpoint: String,
+    pub service_name: String,
+}
+
+#[derive(Debug)]
+pub struct JwtSettings {
+    pub issuer: String,
+    pub audience: String,
+    pub roles_claim: String,
+    pub key: JwtKeySettings,
+}
+
+#[derive(Debug)]
+pub enum JwtKeySettings {
+    Secret(String),
+    PublicKeyFile(PathBuf, Algorithm),
+    Jwks(String, Duration),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimitStoreKind {
+    Memory,
+    Postgres,
+}
+
+#[derive(Debug)]
+pub struct RateLimitSettings {
+    pub default: Policy,
+    pub routes: HashMap<String, Policy>,
+    pub store: RateLimitStoreKind,
+    pub auth_failures: Policy,
+}
+
+#[derive(Debug)]
+pub struct BodyLimitSettings {
+    /// Largest request body in bytes.
+    pub default: usize,
+    pub routes: HashMap<String, usize>,
+}
+
+#[derive(Debug)]
+pub struct CacheSettings {
+    /// How long cached reads are used for.
+    pub ttl: Duration,
+    pub max_facilities: usize,
+    pub max_lists: usize,
+}
+
+#[derive(Debug)]
+pub struct CorsSettings {
+    pub allowed_origins: AllowedOrigins,
+    pub allowed_methods: Vec<Method>,
+    pub allowed_headers: Vec<HeaderName>,
+    pub allow_credentials: bool,
+    pub max_age: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+/// Everything wrong with the given settings, one problem per line.
+#[derive(Debug, PartialEq)]
+pub struct SettingsError(pub Vec<String>);
+
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "invalid settings:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Settings {
+    /// Load settings from the command line and environment, and the settings file they point to.
+    pub fn load(args: SettingsArgs) -> Result<Self, SettingsError> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None => FileSettings::default(),
+        };
+        Self::merge(args, file)
+    }
+
+    /// Layer command line and environment settings over file settings, then validate them.
+    pub fn merge(args: SettingsArgs, file: FileSettings) -> Result<Self, SettingsError> {
+        let mut problems = Vec::new();
+        let jwt_args = args_jwt(&args);
+        let cors = merge_cors(&args, file.cors, &mut problems);
+        let tls = merge_tls(&args, file.server.tls, &mut problems);
+
+        let host = args
+            .host
+            .or(file.server.host)
+            .unwrap_or(String::from("127.0.0.1"));
+        let port = args.port.or(file.server.port).unwrap_or(8080);
+        let metrics_port = args.metrics_port.or(file.server.metrics_port);
+        if metrics_port == Some(port) {
+            problems.push(format!(
+                "metrics port must differ from the server port {port}"
+            ));
+        }
+        let shutdown_delay = args
+            .shutdown_delay_seconds
+            .or(file.server.shutdown_delay_seconds)
+            .unwrap_or(5);
+        let shutdown_timeout = args
+            .shutdown_timeout_seconds
+            .or(file.server.shutdown_timeout_seconds)
+            .unwrap_or(30);
+        let request_timeout = args
+            .request_timeout_seconds
+            .or(file.server.request_timeout_seconds)
+            .unwrap_or(30);
+        if request_timeout == 0 {
+            problems.push(String::from("request timeout must be at least 1 second"));
+        }
+        let database_url = args.database_url.or(file.database.url);
+        if database_url.is_none() {
+            problems.push(String::from(
+                "database URL is required (--database-url, DATABASE_URL or [database] url)",
+            ));
+        }
+
+        let replica_url = args.database_replica_url.or(file.database.replica_url);
+        let read_your_writes = args
+            .database_read_your_writes_seconds
+            .or(file.database.read_your_writes_seconds)
+            .unwrap_or(5);
+        if read_your_writes == 0 {
+            problems.push(String::from(
+                "read your writes window must be at least 1 second",
+            ));
+        }
+
+        let pool_size = args
+            .database_pool_size
+            .or(file.database.pool_size)
+            .unwrap_or(5);
+        if pool_size == 0 {
+            problems.push(String::from("database pool size must be at least 1"));
+        }
+        let pool_timeout = args
+            .database_pool_timeout_seconds
+            .or(file.database.pool_timeout_seconds)
+            .unwrap_or(5);
+        let connect_timeout = args
+            .database_connect_timeout_seconds
+            .or(file.database.connect_timeout_seconds)
+            .unwrap_or(10);
+        let statement_timeout = args
+            .database_statement_timeout_seconds
+            .or(file.database.statement_timeout_seconds)
+            .unwrap_or(30);
+
+        let migrations = match args.migrations.or(file.database.migrations).as_deref() {
+            None | Some("apply") => MigrationMode::Apply,
+            Some("check") => MigrationMode::Check,
+            Some("skip") => MigrationMode::Skip,
+            Some(other) => {
+                problems.push(format!(
+                    "migrations must be apply, check or skip, not {other:?}"
+                ));
+                MigrationMode::Apply
+            }
+        };
+
+        let format = match args.log_format.or(file.log.format).as_deref() {
+            None | Some("text") => LogFormat::Text,
+            Some("json") => LogFormat::Json,
+            Some(other) => {
+                problems.push(format!("log format must be text or json, not {other:?}"));
+                LogFormat::Text
+            }
+        };
+        let filter = args
+            .log_filter
+            .or(file.log.filter)
+            .unwrap_or(String::from("info"));
+
+        let telemetry = args
+            .otlp_endpoint
+            .or(file.telemetry.otlp_endpoint)
+            .map(|endpoint| {
+                if !endpoint.starts_with("http://") && !endpoint.starts_with("https://") {
+                    problems.push(format!(
+                        "OTLP endpoint must be an http(s) URL, not {endpoint:?}"
+                    ));
+                }
+                TelemetrySettings {
+                    // Like the OTLP exporter's own environment variable, a base URL gets the signal path added.
+                    otlp_endpoint: if endpoint.ends_with("/v1/traces") {
+                        endpoint
+                    } else {
+                        format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+                    },
+                    service_name: args
+                        .otel_service_name
+                        .or(file.telemetry.service_name)
+                        .unwrap_or(String::from(env!("CARGO_PKG_NAME"))),
+                }
+            });
+
+        let coordinate_precision = args.coordinate_precision.or(file.coordinate_precision);
+        if let Some(p) = coordinate_precision {
+            if p > MAX_COORDINATE_PRECISION {
+                problems.push(format!(
+                    "coordinate precision must be at most {MAX_COORDINATE_PRECISION}, not {p}"
+                ));
+            }
+        }
+
+        let jwt = merge_jwt(&jwt_args, file.auth.jwt, &mut problems);
+
+        let rate_limit = match args.rate_limit.or(file.rate_limit.default) {
+            None => None,
+            Some(default) => {
+                let default = default.parse().unwrap_or_else(|e| {
+                    problems.push(format!("rate limit {default:?} is {e}"));
+                    Policy {
+                        capacity: 1,
+                        period: Duration::from_secs(1),
+                    }
+                });
+                let routes = match args.rate_limit_routes {
+                    Some(routes) => rate_limit::parse_route_policies(&routes).unwrap_or_else(|e| {
+                        problems.push(format!("route rate limits {routes:?} contain one {e}"));
+                        HashMap::new()
+                    }),
+                    None => file
+                        .rate_limit
+                        .routes
+                        .into_iter()
+                        .filter_map(|(route, policy)| match policy.parse() {
+                            Ok(p) => Some((route, p)),
+                            Err(e) => {
+                                problems.push(format!("rate limit for {route:?} is {e}"));
+                                None
+                            }
+                        })
+                        .collect(),
+                };
+                let store = match args.rate_limit_store.or(file.rate_limit.store).as_deref() {
+                    None | Some("memory") => RateLimitStoreKind::Memory,
+                    Some("postgres") => RateLimitStoreKind::Postgres,
+                    Some(other) => {
+                        problems.push(format!(
+                            "rate limit store must be memory or postgres, not {other:?}"
+                        ));
+                        RateLimitStoreKind::Memory
+                    }
+                };
+                let auth_failures = args
+                    .rate_limit_auth_failures
+                    .or(file.rate_limit.auth_failures)
+                    .unwrap_or_else(|| String::from("10/60"));
+                let auth_failures = auth_failures.parse().unwrap_or_else(|e| {
+                    problems.push(format!(
+                        "failed authentication rate limit {auth_failures:?} is {e}"
+                    ));
+                    default
+                });
+                Some(RateLimitSettings {
+                    default,
+                    routes,
+                    store,
+                    auth_failures,
+                })
+            }
+        };
+
+        let body_limit = BodyLimitSettings {
+            default: match args.body_limit.or(file.body_limit.default) {
+                // The same as axum's own default.
+                None => 2 * 1024 * 1024,
+                Some(size) => body_limit::parse_size(&size).unwrap_or_else(|e| {
+                    problems.push(format!("body limit {size:?} is {e}"));
+                    0
+                }),
+            },
+            routes: match args.body_limit_routes {
+                Some(routes) => body_limit::parse_route_limits(&routes).unwrap_or_else(|e| {
+                    problems.push(format!("route body limits {routes:?} contain one {e}"));
+                    HashMap::new()
+                }),
+                None => file
+                    .body_limit
+                    .routes
+                    .into_iter()
+                    .filter_map(|(route, size)| match body_limit::parse_size(&size) {
+                        Ok(s) => Some((route, s)),
+                        Err(e) => {
+                            problems.push(format!("body limit for {route:?} is {e}"));
+                            None
+                        }
+                    })
+                    .collect(),
+            },
+        };
+
+        let cache_ttl = args
+            .cache_ttl_seconds
+            .or(file.cache.ttl_seconds)
+            .unwrap_or(0);
+        let cache = (cache_ttl > 0).then(|| CacheSettings {
+            ttl: Duration::from_secs(cache_ttl),
+            max_facilities: args
+                .cache_max_facilities
+                .or(file.cache.max_facilities)
+                .unwrap_or(10_000),
+            max_lists: args
+                .cache_max_lists
+                .or(file.cache.max_lists)
+                .unwrap_or(1_000),
+        });
+
+        if !problems.is_empty() {
+            return Err(SettingsError(problems));
+        }
+        Ok(Settings {
+            server: ServerSettings {
+                host,
+                port,
+                metrics_port,
+                shutdown_delay: Duration::from_secs(shutdown_delay),
+                shutdown_timeout: Duration::from_secs(shutdown_timeout),
+                request_timeout: Duration::from_secs(request_timeout),
+                tls,
+            },
+            database: DatabaseSettings {
+                url: database_url.unwrap(),
+                replica_url,
+                read_your_writes: Duration::from_secs(read_your_writes),
+                pool_size,
+                pool_timeout: Duration::from_secs(pool_timeout),
+                connect_timeout: Duration::from_secs(connect_timeout),
+                statement_timeout: (statement_timeout > 0)
+                    .then(|| Duration::from_secs(statement_timeout)),
+                migrations,
+            },
+            log: LogSettings { format, filter },
+            telemetry,
+            coordinate_precision,
+            use_postgis: args.use_postgis.or(file.use_postgis).unwrap_or(true),
+            admin_api_key: args.admin_api_key.or(file.auth.admin_api_key),
+            jwt,
+            rate_limit,
+            body_limit,
+            cors,
+            cache,
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileSettings, SettingsError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| {
+        SettingsError(vec![format!(
+            "unable to read settings file {}: {e}",
+            path.display()
+        )])
+    })?;
+    toml::from_str(&contents).map_err(|e| {
+        SettingsError(vec![format!(
+            "unable to parse settings file {}: {e}",
+            path.display()
+        )])
+    })
+}
+
+/// JWT settings from the command line and environment, in the same shape as the file's.
+fn args_jwt(args: &SettingsArgs) -> FileJwtSettings {
+    FileJwtSettings {
+        issuer: args.jwt_issuer.clone(),
+        audience: args.jwt_audience.clone(),
+        roles_claim: args.jwt_roles_claim.clone(),
+        secret: args.jwt_secret.clone(),
+        public_key_file: args.jwt_public_key_file.clone(),
+        algorithm: args.jwt_algorithm.clone(),
+        jwks: args.jwt_jwks.clone(),
+        jwks_refresh_seconds: args.jwt_jwks_refresh_seconds,
+    }
+}
+
+fn merge_jwt(
+    args: &FileJwtSettings,
+    file: FileJwtSettings,
+    problems: &mut Vec<String>,
+) -> Option<JwtSettings> {
+    let secret = args.secret.clone().or(file.secret);
+    let public_key_file = args.public_key_file.clone().or(file.public_key_file);
+    let jwks = args.jwks.clone().or(file.jwks);
+
+    let key = match (secret, public_key_file, jwks) {
+        (None, None, None) => return None,
+        (Some(secret), None, None) => JwtKeySettings::Secret(secret),
+        (None, Some(path), None) => {
+            let algorithm = args
+                .algorithm
+                .clone()
+                .or(file.algorithm)
+                .unwrap_or(String::from("RS256"));
+            match algorithm.parse() {
+                Ok(a) => JwtKeySettings::PublicKeyFile(path, a),
+                Err(_) => {
+                    problems.push(format!(
+                        "JWT algorithm must be one like RS256 or ES256, not {algorithm:?}"
+                    ));
+                    return None;
+                }
+            }
+        }
+        (None, None, Some(location)) => {
+            let refresh = args
+                .jwks_refresh_seconds
+                .or(file.jwks_refresh_seconds)
+                .unwrap_or(300);
+            JwtKeySettings::Jwks(location, Duration::from_secs(refresh))
+        }
+        _ => {
+            problems.push(String::from(
+                "only one of JWT secret, public key file or JWKS can be set",
+            ));
+            return None;
+        }
+    };
+
+    let issuer = args.issuer.clone().or(file.issuer);
+    let audience = args.audience.clone().or(file.audience);
+    if issuer.is_none() {
+        problems.push(String::from(
+            "JWT issuer is required to validate bearer tokens",
+        ));
+    }
+    if audience.is_none() {
+        problems.push(String::from(
+            "JWT audience is required to validate bearer tokens",
+        ));
+    }
+
+    Some(JwtSettings {
+        issuer: issuer?,
+        audience: audience?,
+        roles_claim: args
+            .roles_claim
+            .clone()
+            .or(file.roles_claim)
+            .unwrap_or(String::from("roles")),
+        key,
+    })
+}
+
+fn merge_tls(
+    args: &SettingsArgs,
+    file: FileTlsSettings,
+    problems: &mut Vec<String>,
+) -> Option<TlsSettings> {
+    let cert_file = args.tls_cert_file.clone().or(file.cert_file);
+    let key_file = args.tls_key_file.clone().or(file.key_file);
+    let client_ca_file = args.tls_client_ca_file.clone().or(file.client_ca_file);
+
+    let (cert_file, key_file) = match (cert_file, key_file) {
+        (Some(cert_file), Some(key_file)) => (cert_file, key_file),
+        (None, None) => {
+            if client_ca_file.is_some() {
+                problems.push(String::from(
+                    "TLS client CA file needs a TLS certificate and key to serve HTTPS with",
+                ));
+            }
+            return None;
+        }
+        _ => {
+            problems.push(String::from(
+                "TLS certificate and key files must be set together",
+            ));
+            return None;
+        }
+    };
+    let client_auth_required = match args.tls_client_auth.clone().or(file.client_auth).as_deref() {
+        None | Some("required") => true,
+        Some("optional") => false,
+        Some(other) => {
+            problems.push(format!(
+                "TLS client auth must be required or optional, not {other:?}"
+            ));
+            true
+        }
+    };
+    let reload_seconds = args
+        .tls_reload_seconds
+        .or(file.reload_seconds)
+        .unwrap_or(10);
+    if reload_seconds == 0 {
+        problems.push(String::from(
+            "TLS reload interval must be at least 1 second",
+        ));
+    }
+
+    Some(TlsSettings {
+        cert_file,
+        key_file,
+        client_ca_file,
+        client_auth_required,
+        reload_interval: Duration::from_secs(reload_seconds),
+    })
+}
+
+/// Split a comma-separated setting from the command line or environment into its items.
+fn split_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn merge_cors(
+    args: &SettingsArgs,
+    file: FileCorsSettings,
+    problems: &mut Vec<String>,
+) -> Option<CorsSettings> {
+    let origins = args
+        .cors_allowed_origins
+        .as_deref()
+        .map(split_list)
+        .or(file.allowed_origins)?;
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_credentials = args
+        .cors_allow_credentials
+        .or(file.allow_credentials)
+        .unwrap_or(false);
+
+    let allowed_origins = if origins.iter().any(|o| o == "*") {
+        if origins.len() > 1 {
+            problems.push(String::from(
+                "CORS origins can be \"*\" or a list of origins, not both",
+            ));
+        }
+        if allow_credentials {
+            problems.push(String::from(
+                "CORS credentials can't be allowed for any origin, list the origins instead",
+            ));
+        }
+        AllowedOrigins::Any
+    } else {
+        AllowedOrigins::List(
+            origins
+                .iter()
+                .filter_map(|origin| {
+                    // Browsers send origins as scheme://host[:port], with no path or trailing slash.
+                    let valid = (origin.starts_with("http://") || origin.starts_with("https://"))
+                        && !origin.split_once("://").unwrap().1.contains('/');
+                    match HeaderValue::from_str(origin) {
+                        Ok(value) if valid => Some(value),
+                        _ => {
+                            problems.push(format!(
+                                "CORS origin must be like \"https://example.com\", not {origin:?}"
+                            ));
+                            None
+                        }
+                    }
+                })
+                .collect(),
+        )
+    };
+
+    let allowed_methods = args
+        .cors_allowed_methods
+        .as_deref()
+        .map(split_list)
+        .or(file.allowed_methods)
+        .unwrap_or(vec![
+            String::from("GET"),
+            String::from("POST"),
+            String::from("DELETE"),
+        ])
+        .iter()
+        .filter_map(|method| match method.to_uppercase().parse() {
+            Ok(m) => Some(m),
+            Err(_) => {
+                problems.push(format!("CORS method {method:?} is not an HTTP method"));
+                None
+            }
+        })
+        .collect();
+    let allowed_headers = args
+        .cors_allowed_headers
+        .as_deref()
+        .map(split_list)
+        .or(file.allowed_headers)
+        .unwrap_or(vec![
+            String::from("authorization"),
+            String::from("content-type"),
+            String::from("x-api-key"),
+            String::from("x-request-id"),
+        ])
+        .iter()
+        .filter_map(|header| match HeaderName::try_from(header.as_str()) {
+            Ok(h) => Some(h),
+            Err(_) => {
+                problems.push(format!("CORS header {header:?} is not a header name"));
+                None
+            }
+        })
+        .collect();
+
+    Some(CorsSettings {
+        allowed_origins,
+        allowed_methods,
+        allowed_headers,
+        allow_credentials,
+        max_age: Duration::from_secs(
+            args.cors_max_age_seconds
+                .or(file.max_age_seconds)
+                .unwrap_or(600),
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(toml: &str) -> FileSettings {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn args_override_file() {
+        let args = SettingsArgs {
+            port: Some(9090),
+            ..Default::default()
+        };
+        let settings = Settings::merge(
+            args,
+            file(
+                r#"
+                [server]
+                host = "127.0.0.1"
+                port = 8080
+
+                [database]
+                url = "postgresql://localhost/db"
+                pool_size = 8
+
+                [rate_limit]
+                default = "100/60"
+                routes = { "POST /facility-imports" = "5/60" }
+                "#,
+            ),
+        )
+        .unwrap();
+        assert_eq!(settings.server.address(), "127.0.0.1:9090");
+        assert_eq!(settings.database.pool_size, 8);
+        assert_eq!(settings.log.format, LogFormat::Text);
+        let rate_limit = settings.rate_limit.unwrap();
+        assert_eq!(rate_limit.routes["POST /facility-imports"].capacity, 5);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let args = SettingsArgs {
+            log_format: Some(String::from("xml")),
+            coordinate_precision: Some(40),
+            jwt_secret: Some(String::from("secret")),
+            ..Default::default()
+        };
+        let SettingsError(problems) = Settings::merge(args, FileSettings::default()).unwrap_err();
+        assert_eq!(problems.len(), 5, "{problems:?}");
+    }
+
+    #[test]
+    fn cors_is_closed_unless_configured() {
+        let database = file("[database]\nurl = \"postgresql://localhost/db\"");
+        let settings = Settings::merge(SettingsArgs::default(), database).unwrap();
+        assert!(settings.cors.is_none());
+
+        let args = SettingsArgs {
+            database_url: Some(String::from("postgresql://localhost/db")),
+            cors_allowed_origins: Some(String::from(
+                "https://map.example.com, http://localhost:3000",
+            )),
+            ..Default::default()
+        };
+        let cors = Settings::merge(args, FileSettings::default())
+            .unwrap()
+            .cors
+            .unwrap();
+        assert_eq!(
+            cors.allowed_origins,
+            AllowedOrigins::List(vec![
+                HeaderValue::from_static("https://map.example.com"),
+                HeaderValue::from_static("http://localhost:3000"),
+            ])
+        );
+        assert_eq!(
+            cors.allowed_methods,
+            [Method::GET, Method::POST, Method::DELETE]
+        );
+        assert!(!cors.allow_credentials);
+    }
+
+    #[test]
+    fn rejects_unsafe_cors() {
+        let args = SettingsArgs {
+            database_url: Some(String::from("postgresql://localhost/db")),
+            cors_allowed_origins: Some(String::from("*")),
+            cors_allow_credentials: Some(true),
+            ..Default::default()
+        };
+        let SettingsError(problems) =
+            Settings::merge(args, file("[cors]\nallowed_headers = [\"bad header\"]")).unwrap_err();
+        assert_eq!(problems.len(), 2, "{problems:?}");
+
+        let args = SettingsArgs {
+            database_url: Some(String::from("postgresql://localhost/db")),
+            cors_allowed_origins: Some(String::from("https://map.example.com/")),
+            ..Default::default()
+        };
+        assert!(Settings::merge(args, FileSettings::default()).is_err());
+    }
+
+    #[test]
+    fn tls_needs_certificate_and_key() {
+        let args = SettingsArgs {
+            database_url: Some(String::from("postgresql://localhost/db")),
+            tls_key_file: Some(PathBuf::from("/etc/tls/key.pem")),
+            ..Default::default()
+        };
+        let toml = "[server.tls]\ncert_file = \"/etc/tls/cert.pem\"\nclient_auth = \"optional\"";
+        let settings = Settings::merge(args, file(toml)).unwrap();
+        let tls = settings.server.tls.unwrap();
+        assert_eq!(tls.cert_file, PathBuf::from("/etc/tls/cert.pem"));
+        assert!(!tls.client_auth_required);
+        assert_eq!(tls.reload_interval, Duration::from_secs(10));
+
+        let args = SettingsArgs {
+            database_url: Some(String::from("postgresql://localhost/db")),
+            tls_client_ca_file: Some(PathBuf::from("/etc/tls/ca.pem")),
+            tls_client_auth: Some(String::from("sometimes")),
+            ..Default::default()
+        };
+        let SettingsError(problems) = Settings::merge(args, FileSettings::default()).unwrap_err();
+        assert_eq!(problems.len(), 1, "{problems:?}");
+
+        let args = SettingsArgs {
+            database_url: Some(String::from("postgresql://localhost/db")),
+            tls_cert_file: Some(PathBuf::from("/etc/tls/cert.pem")),
+            ..Default::default()
+        };
+        assert!(Settings::merge(args, FileSettings::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_file_settings() {
+        assert!(toml::from_str::<FileSettings>("[server]\nhots = \"localhost\"").is_err());
+    }
+}
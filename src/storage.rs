@@ -1,48 +1,179 @@
 use crate::core;
 use crate::models;
-use crate::schema::facilities;
+use crate::schema::{api_keys, facilities};
+use crate::settings::DatabaseSettings;
 use chrono::NaiveDate;
-use deadpool_diesel::postgres::{BuildError, Manager, Pool};
+use deadpool_diesel::postgres::{BuildError, Hook, HookError, Manager, Pool};
 use deadpool_diesel::Runtime;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
 use diesel::prelude::*;
+use diesel::sql_types::{Bool, Double, Text};
+use diesel::upsert::excluded;
 use diesel::PgConnection;
-use serde::{de, Deserialize, Deserializer};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use std::fmt;
 use std::str::FromStr;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
 
 /// Create async Postgres database connection pool.
+///
+/// Checkouts wait at most `pool_timeout` for a free connection and `connect_timeout` for a new one.
+/// New connections get the `statement_timeout`, so a query keeps no connection long after its request gave up.
 pub fn create_database_connection_pool(
-    database_url: String,
+    database: &DatabaseSettings,
     max_size: usize,
 ) -> Result<Pool, BuildError> {
-    let manager = Manager::new(database_url, Runtime::Tokio1);
-    Pool::builder(manager).max_size(max_size).build()
+    create_connection_pool(&database.url, database, max_size)
+}
+
+/// Create a connection pool for the read replica, like the primary's, if there is one.
+pub fn create_replica_connection_pool(
+    database: &DatabaseSettings,
+    max_size: usize,
+) -> Option<Result<Pool, BuildError>> {
+    database
+        .replica_url
+        .as_ref()
+        .map(|url| create_connection_pool(url, database, max_size))
 }
 
-/// Write Facility record to persistent storage, returning newly created facility if successful.
+fn create_connection_pool(
+    url: &str,
+    database: &DatabaseSettings,
+    max_size: usize,
+) -> Result<Pool, BuildError> {
+    let manager = Manager::new(url, Runtime::Tokio1);
+    let statement_timeout = database.statement_timeout.unwrap_or_default();
+    Pool::builder(manager)
+        .max_size(max_size)
+        .wait_timeout(Some(database.pool_timeout))
+        .create_timeout(Some(database.connect_timeout))
+        .post_create(Hook::async_fn(move |client, _| {
+            Box::pin(async move {
+                client
+                    .interact(move |conn| set_statement_timeout(conn, statement_timeout))
+                    .await
+                    .map_err(|e| HookError::message(format!("{e:?}")))?
+                    .map_err(|e| HookError::message(e.to_string()))
+            })
+        }))
+        .runtime(Runtime::Tokio1)
+        .build()
+}
+
+/// Have Postgres cancel statements on this connection running longer than `timeout`, or never when zero.
+pub fn set_statement_timeout(
+    conn: &mut PgConnection,
+    timeout: Duration,
+) -> Result<(), diesel::result::Error> {
+    // SET takes no bind parameters, and the value is a number.
+    diesel::sql_query(format!("SET statement_timeout = {}", timeout.as_millis())).execute(conn)?;
+    Ok(())
+}
+
+/// Write Facility record to persistent storage, returning the stored facility and what happened to it.
+///
+/// How an existing record with the same UID is handled depends on `on_conflict`.
 pub fn write_facility(
     conn: &mut PgConnection,
     facility: core::Facility,
-) -> Result<core::Facility, diesel::result::Error> {
-    // Return a copy of the facility we inserted. Doesn't play nicely with diesel, so using this hack as input/output should be the same for the moment.
-    let returned_hack = facility.clone();
+    on_conflict: OnConflict,
+) -> Result<(core::Facility, WriteOutcome), diesel::result::Error> {
+    let uid = facility.uid.clone();
     let modeled_facility = models::Facility::from(facility);
 
-    let output_result = diesel::insert_into(facilities::table)
-        .values(modeled_facility)
-        .execute(conn);
-
-    let _ = match output_result {
-        Ok(_) => (),
-        Err(e) => return Err(e), // Error saving new facility.
-    };
     // TODO: Fix leaking the abstraction by returning Err like this.
+    match upsert_facility(conn, &modeled_facility, on_conflict)? {
+        Some((stored, outcome)) => Ok((from_stored(stored)?, outcome)),
+        // Whatever is already stored was kept, so hand that back instead of the input.
+        None => Ok((read_facility(conn, uid)?, WriteOutcome::Unchanged)),
+    }
+}
+
+/// Write many Facility records to persistent storage in a single transaction, reporting what happened to each.
+///
+/// With `OnConflict::Error` a single duplicate UID rolls back the whole batch.
+pub fn write_facilities(
+    conn: &mut PgConnection,
+    facilities: Vec<core::Facility>,
+    on_conflict: OnConflict,
+) -> Result<Vec<WriteReport>, diesel::result::Error> {
+    conn.transaction(|conn| {
+        facilities
+            .into_iter()
+            .map(|facility| {
+                let modeled_facility = models::Facility::from(facility);
+                let outcome = match upsert_facility(conn, &modeled_facility, on_conflict)? {
+                    Some((_, outcome)) => outcome,
+                    None => WriteOutcome::Unchanged,
+                };
+                Ok(WriteReport {
+                    uid: modeled_facility.uid,
+                    outcome,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Insert a single modeled facility with Postgres' ON CONFLICT handling.
+///
+/// Returns the row as Postgres stored it, or None if nothing was written.
+fn upsert_facility(
+    conn: &mut PgConnection,
+    facility: &models::Facility,
+    on_conflict: OnConflict,
+) -> Result<Option<(models::Facility, WriteOutcome)>, diesel::result::Error> {
+    let insert = diesel::insert_into(facilities::table).values(facility);
 
-    // TODO: Handle the case of duplicate key: DatabaseError(UniqueViolation, "duplicate key value violates unique constraint \"facilities_pkey\"")
-    // TODO: Query/return what we're inserting instead of 'returned_hack'.
-    // TODO: Properly handle storage errors here.
+    match on_conflict {
+        OnConflict::Error => insert
+            .returning(models::Facility::as_returning())
+            .get_result(conn)
+            .map(|stored| Some((stored, WriteOutcome::Inserted))),
+        OnConflict::Ignore => insert
+            .on_conflict_do_nothing()
+            .returning(models::Facility::as_returning())
+            .get_result(conn)
+            .optional()
+            .map(|stored| stored.map(|s| (s, WriteOutcome::Inserted))),
+        OnConflict::Update => {
+            use diesel::query_dsl::methods::FilterDsl;
 
-    Ok(returned_hack)
+            // Only touch rows that actually differ so we can tell "updated" apart from "unchanged".
+            // xmax is zero for freshly inserted row versions and non-zero for updated ones.
+            let stored = insert
+                .on_conflict(facilities::uid)
+                .do_update()
+                .set(facility)
+                .filter(
+                    facilities::company
+                        .is_distinct_from(excluded(facilities::company))
+                        .or(facilities::segment.is_distinct_from(excluded(facilities::segment)))
+                        .or(facilities::technology
+                            .is_distinct_from(excluded(facilities::technology)))
+                        .or(facilities::latitude.is_distinct_from(excluded(facilities::latitude)))
+                        .or(facilities::longitude.is_distinct_from(excluded(facilities::longitude)))
+                        .or(facilities::announcement_date
+                            .is_distinct_from(excluded(facilities::announcement_date)))
+                        .or(facilities::estimated_investment
+                            .is_distinct_from(excluded(facilities::estimated_investment))),
+                )
+                .returning((models::Facility::as_returning(), sql::<Bool>("xmax = 0")))
+                .get_result::<(models::Facility, bool)>(conn)
+                .optional()?;
+            Ok(stored.map(|(s, inserted)| {
+                if inserted {
+                    (s, WriteOutcome::Inserted)
+                } else {
+                    (s, WriteOutcome::Updated)
+                }
+            }))
+        }
+    }
 }
 
 /// Read a Facility record from persistent storage based on its UID.
@@ -57,24 +188,169 @@ pub fn read_facility(
 
     // TODO: Fix leaking the abstraction by returning Err like this.
     match db_output_results {
-        Ok(r) => {
-            let f: core::Facility = r
-                .try_into()
-                .expect("Got incompatible Facility from storage");
-            // TODO: Actually pass and handle this error ^ properly
-            Ok(f)
-        }
+        Ok(r) => from_stored(r),
         Err(e) => Err(e),
     }
 }
 
+/// How spatial filters are run against storage.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpatialIndex {
+    /// Query the indexed PostGIS `location` geography column.
+    PostGis,
+    /// Query the plain latitude and longitude columns.
+    Plain,
+}
+
+/// Find out whether storage has the PostGIS `location` column to run spatial filters against.
+///
+/// The column is only created by migrations when the PostGIS extension is available.
+pub fn detect_spatial_index(
+    conn: &mut PgConnection,
+) -> Result<SpatialIndex, diesel::result::Error> {
+    let has_location = diesel::select(sql::<Bool>(
+        "EXISTS (SELECT 1 FROM information_schema.columns \
+         WHERE table_schema = current_schema() \
+         AND table_name = 'facilities' AND column_name = 'location')",
+    ))
+    .get_result::<bool>(conn)?;
+
+    if has_location {
+        Ok(SpatialIndex::PostGis)
+    } else {
+        Ok(SpatialIndex::Plain)
+    }
+}
+
 /// List stored facilities.
 pub fn list_facilities(
     conn: &mut PgConnection,
     filter: FacilitiesFilter,
+    spatial_index: SpatialIndex,
 ) -> Result<Vec<core::Facility>, diesel::result::Error> {
+    let (offset, limit) = (filter.offset, filter.limit);
+    let mut query = filtered_query(filter, spatial_index);
+
+    // Add pagination
+    query = query.offset(i64::from(offset)).limit(i64::from(limit));
+
+    let db_output_results = query.select(models::Facility::as_select()).load(conn);
+
+    match db_output_results {
+        // Convert databases response into core::Facilities.
+        Ok(r) => r.into_iter().map(from_stored).collect(),
+        Err(e) => Err(e),
+    }
+}
+
+/// Convert a stored row to its core type, failing like any other storage error if it's no longer valid.
+fn from_stored<S, T>(stored: S) -> Result<T, diesel::result::Error>
+where
+    T: TryFrom<S>,
+    T::Error: std::error::Error + Send + Sync + 'static,
+{
+    T::try_from(stored).map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))
+}
+
+/// Check the database answers queries.
+pub fn ping(conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    diesel::sql_query("SELECT 1").execute(conn).map(|_| ())
+}
+
+/// Count stored facilities in each segment.
+pub fn count_facilities_by_segment(
+    conn: &mut PgConnection,
+) -> Result<Vec<(String, i64)>, diesel::result::Error> {
+    facilities::table
+        .group_by(facilities::segment)
+        .select((facilities::segment, diesel::dsl::count_star()))
+        .load(conn)
+}
+
+/// Read every stored facility, ordered by UID.
+pub fn export_facilities(
+    conn: &mut PgConnection,
+) -> Result<Vec<core::Facility>, diesel::result::Error> {
+    facilities::table
+        .order(facilities::uid)
+        .select(models::Facility::as_select())
+        .load(conn)?
+        .into_iter()
+        .map(from_stored)
+        .collect()
+}
+
+/// Most facilities a search without PostGIS loads to test against its region.
+pub const MAX_SEARCH_CANDIDATES: i64 = 10_000;
+
+/// List stored facilities inside a region, ordered by UID.
+///
+/// With PostGIS the containment test runs in storage. Otherwise storage narrows facilities down to the
+/// region's bounding box and the test happens here, which gives up on boxes holding too many facilities.
+pub fn search_facilities(
+    conn: &mut PgConnection,
+    search: FacilitiesSearch,
+    spatial_index: SpatialIndex,
+) -> Result<Vec<core::Facility>, SearchError> {
+    let region = search.region;
+    let (offset, limit) = (search.filter.offset, search.filter.limit);
+    let query = filtered_query(search.filter, spatial_index).order(facilities::uid);
+
+    if spatial_index == SpatialIndex::PostGis {
+        return query
+            .filter(in_region(&region))
+            .offset(i64::from(offset))
+            .limit(i64::from(limit))
+            .select(models::Facility::as_select())
+            .load(conn)?
+            .into_iter()
+            .map(|x| from_stored(x).map_err(SearchError::from))
+            .collect();
+    }
+
+    let candidates = filter_bbox(query, region.bounding_box(), spatial_index)
+        .limit(MAX_SEARCH_CANDIDATES + 1)
+        .select(models::Facility::as_select())
+        .load(conn)?;
+    let too_many = candidates.len() as i64 > MAX_SEARCH_CANDIDATES;
+    let wanted = offset as usize + limit as usize;
+    let mut found = Vec::new();
+    for candidate in candidates {
+        let facility: core::Facility = from_stored(candidate)?;
+        if region.contains(&facility.latitude, &facility.longitude) {
+            found.push(facility);
+            if found.len() == wanted {
+                break;
+            }
+        }
+    }
+    // Candidates come in UID order, so a full page found among them is the right one.
+    if too_many && found.len() < wanted {
+        return Err(SearchError::TooManyCandidates);
+    }
+    Ok(found.into_iter().skip(offset as usize).collect())
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    /// Without PostGIS, more than `MAX_SEARCH_CANDIDATES` facilities fell within the region's bounding box.
+    TooManyCandidates,
+    Storage(diesel::result::Error),
+}
+
+impl From<diesel::result::Error> for SearchError {
+    fn from(e: diesel::result::Error) -> Self {
+        SearchError::Storage(e)
+    }
+}
+
+/// Build a query for facilities matching a filter, without pagination.
+fn filtered_query<'a>(
+    filter: FacilitiesFilter,
+    spatial_index: SpatialIndex,
+) -> facilities::BoxedQuery<'a, Pg> {
     // A basic DB query we will build off of.
-    let mut query = facilities::table.into_boxed::<diesel::pg::Pg>();
+    let mut query = facilities::table.into_boxed::<Pg>();
 
     // Optional filters may be added to query.
     if let Some(segment) = filter.segment {
@@ -89,36 +365,120 @@ pub fn list_facilities(
     if let Some(announced_after) = filter.announced_after {
         query = query.filter(facilities::announcement_date.gt(announced_after));
     }
+    if let Some(bbox) = filter.bbox {
+        query = filter_bbox(query, bbox, spatial_index);
+    }
+    if let Some(near) = filter.near {
+        let radius_meters = near.radius_meters;
+        let (latitude, longitude) = (f64::from(near.latitude), f64::from(near.longitude));
+        query = match spatial_index {
+            SpatialIndex::PostGis => query.filter(
+                sql::<Bool>("ST_DWithin(location, ST_SetSRID(ST_MakePoint(")
+                    .bind::<Double, _>(longitude)
+                    .sql(", ")
+                    .bind::<Double, _>(latitude)
+                    .sql("), 4326)::geography, ")
+                    .bind::<Double, _>(radius_meters)
+                    .sql(")"),
+            ),
+            // Haversine distance on a sphere with Earth's mean radius. It can be off from
+            // PostGIS' spheroid distances by a fraction of a percent.
+            SpatialIndex::Plain => query.filter(
+                sql::<Bool>(
+                    "2 * 6371008.8 * asin(sqrt(least(1.0, \
+                     power(sin(radians(latitude - ",
+                )
+                .bind::<Double, _>(latitude)
+                .sql(") / 2), 2) + cos(radians(latitude)) * cos(radians(")
+                .bind::<Double, _>(latitude)
+                .sql(")) * power(sin(radians(longitude - ")
+                .bind::<Double, _>(longitude)
+                .sql(") / 2), 2)))) <= ")
+                .bind::<Double, _>(radius_meters),
+            ),
+        };
+    }
 
-    // Add pagination
-    query = query
-        .offset(i64::from(filter.offset))
-        .limit(i64::from(filter.limit));
-
-    let db_output_results = query.select(models::Facility::as_select()).load(conn);
+    query
+}
 
-    match db_output_results {
-        // Convert databases response into core::Facilities.
-        Ok(r) => Ok(r
-            .into_iter()
-            .map(|x| {
-                core::Facility::try_from(x).expect("Got incompatible Facility from storage")
-                // TODO: Actually pass and handle this error ^ properly
-            })
-            .collect()),
-        Err(e) => Err(e),
+/// Narrow a query down to facilities inside a bounding box.
+fn filter_bbox<'a>(
+    query: facilities::BoxedQuery<'a, Pg>,
+    bbox: core::BoundingBox,
+    spatial_index: SpatialIndex,
+) -> facilities::BoxedQuery<'a, Pg> {
+    let crosses_antimeridian = bbox.crosses_antimeridian();
+    let (west, east) = (f64::from(bbox.west), f64::from(bbox.east));
+    let (south, north) = (f64::from(bbox.south), f64::from(bbox.north));
+    match spatial_index {
+        SpatialIndex::PostGis if crosses_antimeridian => query.filter(
+            intersects_envelope(west, south, 180.0, north)
+                .or(intersects_envelope(-180.0, south, east, north)),
+        ),
+        SpatialIndex::PostGis => query.filter(intersects_envelope(west, south, east, north)),
+        SpatialIndex::Plain if crosses_antimeridian => query
+            .filter(facilities::latitude.between(south, north))
+            .filter(
+                facilities::longitude
+                    .ge(west)
+                    .or(facilities::longitude.le(east)),
+            ),
+        SpatialIndex::Plain => query
+            .filter(facilities::latitude.between(south, north))
+            .filter(facilities::longitude.between(west, east)),
     }
 }
 
+/// Predicate for facilities whose PostGIS location lies inside a region.
+///
+/// Like `intersects_envelope`, the region's edges are planar so results match the plain columns' search.
+fn in_region(
+    region: &core::Region,
+) -> Box<dyn BoxableExpression<facilities::table, Pg, SqlType = Bool>> {
+    let covers =
+        |polygon: String| -> Box<dyn BoxableExpression<facilities::table, Pg, SqlType = Bool>> {
+            Box::new(
+                sql::<Bool>("ST_Covers(ST_SetSRID(ST_GeomFromGeoJSON(")
+                    .bind::<Text, _>(polygon)
+                    .sql("), 4326), location::geometry)"),
+            )
+        };
+    region
+        .geojson_polygons()
+        .into_iter()
+        .map(covers)
+        .reduce(|a, b| Box::new(a.or(b)))
+        .unwrap_or_else(|| Box::new(sql::<Bool>("FALSE")))
+}
+
+/// Predicate for facilities whose PostGIS location falls within a longitude/latitude envelope.
+///
+/// The envelope is planar, with edges along lines of latitude and longitude like the plain columns'
+/// filter, rather than a geography polygon whose edges would follow great circles.
+fn intersects_envelope(
+    west: f64,
+    south: f64,
+    east: f64,
+    north: f64,
+) -> Box<dyn BoxableExpression<facilities::table, Pg, SqlType = Bool>> {
+    Box::new(
+        sql::<Bool>("location::geometry && ST_MakeEnvelope(")
+            .bind::<Double, _>(west)
+            .sql(", ")
+            .bind::<Double, _>(south)
+            .sql(", ")
+            .bind::<Double, _>(east)
+            .sql(", ")
+            .bind::<Double, _>(north)
+            .sql(", 4326)"),
+    )
+}
+
 /// Delete a Facility record from persistent storage based on its UID.
 pub fn delete_facility(conn: &mut PgConnection, uid: String) -> Result<(), diesel::result::Error> {
-    let query_result =
-        diesel::delete(facilities::table.filter(facilities::uid.eq(uid))).execute(conn);
-
-    let n_deleted = match query_result {
-        Ok(r) => r,
-        Err(e) => return Err(e),
-    };
+    let n_deleted =
+        diesel::delete(facilities::table.filter(facilities::uid.eq(uid))).execute(conn)?;
 
     if n_deleted == 0 {
         // Nothing was found. Nothing was deleted.
@@ -128,23 +488,220 @@ pub fn delete_facility(conn: &mut PgConnection, uid: String) -> Result<(), diese
     Ok(())
 }
 
+/// Write a new API key record to persistent storage.
+pub fn write_api_key(
+    conn: &mut PgConnection,
+    api_key: models::NewApiKey,
+) -> Result<core::ApiKey, diesel::result::Error> {
+    let stored = diesel::insert_into(api_keys::table)
+        .values(api_key)
+        .returning(models::ApiKey::as_returning())
+        .get_result(conn)?;
+    from_stored(stored)
+}
+
+/// Read an API key record and its secret hash from persistent storage based on its ID.
+pub fn read_api_key(
+    conn: &mut PgConnection,
+    id: String,
+) -> Result<(core::ApiKey, String), diesel::result::Error> {
+    let stored = api_keys::table
+        .filter(api_keys::id.eq(id))
+        .select(models::ApiKey::as_select())
+        .first(conn)?;
+    let secret_hash = stored.secret_hash.clone();
+    Ok((from_stored(stored)?, secret_hash))
+}
+
+/// List stored API keys.
+pub fn list_api_keys(conn: &mut PgConnection) -> Result<Vec<core::ApiKey>, diesel::result::Error> {
+    let stored = api_keys::table
+        .order(api_keys::created_at)
+        .select(models::ApiKey::as_select())
+        .load(conn)?;
+    stored.into_iter().map(from_stored).collect()
+}
+
+/// Delete an API key record from persistent storage based on its ID.
+pub fn delete_api_key(conn: &mut PgConnection, id: String) -> Result<(), diesel::result::Error> {
+    let n_deleted = diesel::delete(api_keys::table.filter(api_keys::id.eq(id))).execute(conn)?;
+
+    if n_deleted == 0 {
+        return Err(diesel::result::Error::NotFound);
+    }
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct RateLimitTake {
+    #[diesel(sql_type = Bool)]
+    allowed: bool,
+    #[diesel(sql_type = Double)]
+    remaining: f64,
+}
+
+/// Take a token from a shared rate limit bucket, refilling it first.
+///
+/// Returns whether a token was available and how many are left.
+pub fn take_rate_limit_token(
+    conn: &mut PgConnection,
+    key: String,
+    capacity: f64,
+    refill_per_second: f64,
+) -> Result<(bool, f64), diesel::result::Error> {
+    let taken = diesel::sql_query("SELECT allowed, remaining FROM rate_limit_take($1, $2, $3)")
+        .bind::<Text, _>(key)
+        .bind::<Double, _>(capacity)
+        .bind::<Double, _>(refill_per_second)
+        .get_result::<RateLimitTake>(conn)?;
+    Ok((taken.allowed, taken.remaining))
+}
+
+/// Delete shared rate limit buckets unused for longer than `idle`.
+pub fn delete_idle_rate_limit_buckets(
+    conn: &mut PgConnection,
+    idle: Duration,
+) -> Result<usize, diesel::result::Error> {
+    diesel::sql_query(
+        "DELETE FROM rate_limit_buckets WHERE updated_at < clock_timestamp() - make_interval(secs => $1)",
+    )
+    .bind::<Double, _>(idle.as_secs_f64())
+    .execute(conn)
+}
+
 /// Filter list of facilities in storage.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, IntoParams, ToSchema)]
+#[into_params(parameter_in = Query)]
 pub struct FacilitiesFilter {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub segment: Option<String>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub technology: Option<String>,
+    /// Only facilities announced before this "YYYY-MM-DD" date.
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub announced_before: Option<NaiveDate>,
+    /// Only facilities announced after this "YYYY-MM-DD" date.
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub announced_after: Option<NaiveDate>,
+    /// Only facilities inside a "west,south,east,north" box.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    #[schema(value_type = Option<String>, example = "-90,30,-80,40")]
+    #[param(value_type = Option<String>, example = "-90,30,-80,40")]
+    pub bbox: Option<core::BoundingBox>,
+    /// Only facilities within a "latitude,longitude,radius_meters" circle.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    #[schema(value_type = Option<String>, example = "35.6,-88.8,5000")]
+    #[param(value_type = Option<String>, example = "35.6,-88.8,5000")]
+    pub near: Option<core::Circle>,
     #[serde(default = "default_offset")]
+    #[schema(default = 0)]
+    #[param(default = 0)]
     pub offset: u32,
     #[serde(default = "default_limit")]
+    #[schema(default = 100)]
+    #[param(default = 100)]
     pub limit: u32,
 }
 
+impl FacilitiesFilter {
+    /// Key for the facilities this filter selects, the same however its query string was written.
+    ///
+    /// Built from the parsed values as a query string, with text percent-encoded so it can't pass for other fields.
+    pub fn cache_key(&self) -> String {
+        let text = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map(|v| utf8_percent_encode(v, NON_ALPHANUMERIC).to_string())
+                .unwrap_or_default()
+        };
+        let date = |value: Option<NaiveDate>| value.map(|d| d.to_string()).unwrap_or_default();
+        let bbox = self
+            .bbox
+            .as_ref()
+            .map(|b| {
+                format!(
+                    "{},{},{},{}",
+                    f64::from(b.west.clone()),
+                    f64::from(b.south.clone()),
+                    f64::from(b.east.clone()),
+                    f64::from(b.north.clone())
+                )
+            })
+            .unwrap_or_default();
+        let near = self
+            .near
+            .as_ref()
+            .map(|c| {
+                format!(
+                    "{},{},{}",
+                    f64::from(c.latitude.clone()),
+                    f64::from(c.longitude.clone()),
+                    c.radius_meters
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            "segment={}&technology={}&announced_before={}&announced_after={}&bbox={bbox}&near={near}&offset={}&limit={}",
+            text(&self.segment),
+            text(&self.technology),
+            date(self.announced_before),
+            date(self.announced_after),
+            self.offset,
+            self.limit
+        )
+    }
+}
+
+/// What to do when writing a facility whose UID is already in storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    /// Refuse the write with a unique violation.
+    #[default]
+    Error,
+    /// Keep the stored facility and skip the write.
+    Ignore,
+    /// Overwrite the stored facility with the new values.
+    Update,
+}
+
+/// Options for writing facilities to storage.
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WriteOptions {
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+}
+
+/// What happened to a single facility written to storage.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteOutcome {
+    Inserted,
+    Updated,
+    Unchanged,
+}
+
+/// Outcome of writing one facility as part of a batch.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WriteReport {
+    pub uid: String,
+    pub outcome: WriteOutcome,
+}
+
+/// Search for facilities inside a region, alongside the usual list filters.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct FacilitiesSearch {
+    /// GeoJSON Polygon or MultiPolygon geometry, which may have holes and cross the antimeridian.
+    #[schema(value_type = Object, example = json!({
+        "type": "Polygon",
+        "coordinates": [[[-90.0, 30.0], [-80.0, 30.0], [-80.0, 40.0], [-90.0, 30.0]]]
+    }))]
+    pub region: core::Region,
+    #[serde(flatten)]
+    pub filter: FacilitiesFilter,
+}
+
 fn default_limit() -> u32 {
     let limit: u32 = 100;
     limit
@@ -170,3 +727,184 @@ where
         Some(s) => FromStr::from_str(s).map_err(de::Error::custom).map(Some),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrations;
+
+    /// Connection to a scratch database with PostGIS available, migrated, for tests that need one.
+    fn postgis_connection() -> PgConnection {
+        let url = std::env::var("POSTGIS_TEST_DATABASE_URL")
+            .expect("POSTGIS_TEST_DATABASE_URL names a database with PostGIS available");
+        let mut conn = PgConnection::establish(&url).unwrap();
+        migrations::run_pending_migrations(&mut conn).unwrap();
+        conn
+    }
+
+    fn filter(bbox: &str) -> FacilitiesFilter {
+        FacilitiesFilter {
+            segment: None,
+            technology: None,
+            announced_before: None,
+            announced_after: None,
+            bbox: Some(bbox.parse().unwrap()),
+            near: None,
+            offset: 0,
+            limit: 100,
+        }
+    }
+
+    fn listed_uids(
+        conn: &mut PgConnection,
+        bbox: &str,
+        spatial_index: SpatialIndex,
+    ) -> Vec<String> {
+        let mut uids: Vec<String> = list_facilities(conn, filter(bbox), spatial_index)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uid)
+            .collect();
+        uids.sort();
+        uids
+    }
+
+    fn insert_facilities(
+        conn: &mut PgConnection,
+        points: &[(&str, f64, f64)],
+    ) -> Result<(), diesel::result::Error> {
+        for &(uid, latitude, longitude) in points {
+            let facility = core::Facility::new(
+                uid.to_string(),
+                String::from("c"),
+                String::from("s"),
+                String::from("t"),
+                latitude,
+                longitude,
+                NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+                None,
+            )
+            .unwrap();
+            write_facility(conn, facility, OnConflict::Error)?;
+        }
+        Ok(())
+    }
+
+    fn searched_uids(
+        conn: &mut PgConnection,
+        region: &core::Region,
+        offset: u32,
+        spatial_index: SpatialIndex,
+    ) -> Vec<String> {
+        let search = FacilitiesSearch {
+            region: region.clone(),
+            filter: FacilitiesFilter {
+                bbox: None,
+                offset,
+                limit: 2,
+                ..filter("-180,-90,180,90")
+            },
+        };
+        search_facilities(conn, search, spatial_index)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uid)
+            .collect()
+    }
+
+    #[test]
+    fn cache_key_follows_parsed_values() {
+        let key = |segment: &str, bbox: &str| {
+            FacilitiesFilter {
+                segment: Some(segment.to_string()),
+                ..filter(bbox)
+            }
+            .cache_key()
+        };
+        assert_eq!(key("s", "-90,30,-80,40"), key("s", "-90.0,30,-80.00,40"));
+        assert_ne!(key("s", "-90,30,-80,40"), key("s", "-90,30,-80,41"));
+        assert_ne!(
+            key("s&technology=t", "-90,30,-80,40"),
+            FacilitiesFilter {
+                segment: Some(String::from("s")),
+                technology: Some(String::from("t")),
+                ..filter("-90,30,-80,40")
+            }
+            .cache_key()
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a PostGIS database in POSTGIS_TEST_DATABASE_URL"]
+    fn bbox_filters_agree_with_and_without_postgis() {
+        let mut conn = postgis_connection();
+        assert_eq!(
+            detect_spatial_index(&mut conn).unwrap(),
+            SpatialIndex::PostGis
+        );
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            insert_facilities(
+                conn,
+                &[
+                    ("inside-south-edge", 40.01, 0.0),
+                    ("inside-north-edge", 59.99, 100.0),
+                    ("inside-west", 45.0, -170.0),
+                    ("outside-south", 39.99, 0.0),
+                    ("outside-east", 45.0, 175.0),
+                ],
+            )?;
+
+            // Wider than 180° of longitude, where a geography polygon would wrap the short way,
+            // and far enough north that its edges would bow away from the lines of latitude.
+            for bbox in ["-171,40,171,60", "171,40,-171,60"] {
+                let plain = listed_uids(conn, bbox, SpatialIndex::Plain);
+                assert_eq!(listed_uids(conn, bbox, SpatialIndex::PostGis), plain);
+            }
+            assert_eq!(
+                listed_uids(conn, "-171,40,171,60", SpatialIndex::PostGis),
+                ["inside-north-edge", "inside-south-edge", "inside-west"]
+            );
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs a PostGIS database in POSTGIS_TEST_DATABASE_URL"]
+    fn region_searches_agree_with_and_without_postgis() {
+        let mut conn = postgis_connection();
+        // A square across the antimeridian with a hole in it, far enough north for great circles to matter.
+        let region: core::Region = serde_json::from_value(serde_json::json!({
+            "type": "Polygon",
+            "coordinates": [
+                [[170.0, 50.0], [-170.0, 50.0], [-170.0, 60.0], [170.0, 60.0], [170.0, 50.0]],
+                [[178.0, 54.0], [-178.0, 54.0], [-178.0, 56.0], [178.0, 56.0], [178.0, 54.0]]
+            ]
+        }))
+        .unwrap();
+        conn.test_transaction::<_, diesel::result::Error, _>(|conn| {
+            insert_facilities(
+                conn,
+                &[
+                    ("a-east", 55.0, 172.0),
+                    ("b-west", 55.0, -172.0),
+                    ("c-north-edge", 59.99, 180.0),
+                    ("d-hole", 55.0, 180.0),
+                    ("e-outside", 55.0, 0.0),
+                ],
+            )?;
+
+            let pages: Vec<Vec<String>> = [0, 2]
+                .into_iter()
+                .map(|offset| searched_uids(conn, &region, offset, SpatialIndex::PostGis))
+                .collect();
+            assert_eq!(pages, [vec!["a-east", "b-west"], vec!["c-north-edge"]]);
+            for offset in [0, 2] {
+                assert_eq!(
+                    searched_uids(conn, &region, offset, SpatialIndex::Plain),
+                    pages[offset as usize / 2]
+                );
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,185 @@
+use crate::settings::{LogFormat, LogSettings, TelemetrySettings};
+use axum::http::HeaderMap;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry::{global, Context};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Level;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+/// Set up logging, and span export if a collector is configured.
+///
+/// Returns the tracer provider, which must be shut down before exiting to flush buffered spans.
+pub fn init_tracing(
+    log: &LogSettings,
+    telemetry: Option<&TelemetrySettings>,
+    writer: BoxMakeWriter,
+) -> Result<Option<SdkTracerProvider>, String> {
+    let filter = EnvFilter::try_new(&log.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    let fmt_layer = match log.format {
+        LogFormat::Text => tracing_subscriber::fmt::layer()
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+    };
+
+    let provider = match telemetry {
+        Some(telemetry) => {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&telemetry.otlp_endpoint)
+                .build()
+                .map_err(|e| format!("unable to set up OTLP span export {e}"))?;
+            Some(
+                SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(
+                        Resource::builder()
+                            .with_service_name(telemetry.service_name.clone())
+                            .build(),
+                    )
+                    .build(),
+            )
+        }
+        None => None,
+    };
+    // Export our request and storage spans whatever the log filter, but not other crates' noise.
+    let otel_layer = provider.as_ref().map(|p| {
+        tracing_opentelemetry::layer()
+            .with_tracer(p.tracer(env!("CARGO_PKG_NAME")))
+            .with_filter(Targets::new().with_target(env!("CARGO_PKG_NAME"), Level::DEBUG))
+    });
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(otel_layer)
+        .try_init()
+        .map_err(|e| format!("unable to set up tracing {e}"))?;
+    Ok(provider)
+}
+
+/// Trace context sent by the client in W3C traceparent and tracestate headers, if any.
+pub fn remote_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::TraceContextExt;
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{self, Receiver};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn extracts_traceparent() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+        let cx = remote_context(&headers);
+        let span_context = cx.span().span_context().clone();
+        assert!(span_context.is_remote());
+        assert_eq!(
+            span_context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+
+        assert!(!remote_context(&HeaderMap::new())
+            .span()
+            .span_context()
+            .is_valid());
+    }
+
+    /// Accept one connection and answer one request with an empty 200, sending back its request line and body.
+    fn stub_collector() -> (String, Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            (&stream)
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            sender
+                .send((request_line.trim_end().to_string(), body))
+                .unwrap();
+        });
+        (format!("http://{address}/v1/traces"), receiver)
+    }
+
+    #[test]
+    fn exports_spans_to_the_collector() {
+        let (otlp_endpoint, requests) = stub_collector();
+        let log = LogSettings {
+            format: LogFormat::Text,
+            filter: String::from("off"),
+        };
+        let telemetry = TelemetrySettings {
+            otlp_endpoint,
+            service_name: String::from("stubbed-service"),
+        };
+        let provider = init_tracing(&log, Some(&telemetry), BoxMakeWriter::new(io::sink))
+            .unwrap()
+            .unwrap();
+
+        tracing::info_span!("stubbed_span").in_scope(|| {});
+        provider.shutdown().unwrap();
+
+        let (request_line, body) = requests.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+        let contains = |text: &str| body.windows(text.len()).any(|w| w == text.as_bytes());
+        assert!(contains("stubbed-service"));
+        assert!(contains("stubbed_span"));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::settings::TlsSettings;
+use axum::serve::Listener;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+
+/// How long a client gets to finish its TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Handshaken connections waiting for the server to take them.
+const ACCEPTED_BACKLOG: usize = 128;
+
+/// Server TLS configuration, swapped out whenever its files change.
+pub struct TlsConfig {
+    settings: TlsSettings,
+    current: RwLock<Arc<ServerConfig>>,
+}
+
+impl TlsConfig {
+    /// Load the certificate, key and client CA bundle named in settings.
+    pub fn load(settings: &TlsSettings) -> Result<Self, String> {
+        Ok(TlsConfig {
+            current: RwLock::new(Arc::new(server_config(settings)?)),
+            settings: settings.clone(),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+
+    /// Check the files for changes every reload interval, and use them for new connections once they do.
+    ///
+    /// Files that fail to load, like a certificate written before its key, leave the previous configuration in
+    /// place, and are tried again on the next check.
+    pub async fn reload_on_change(self: Arc<Self>) {
+        let mut loaded = self.modified();
+        let mut interval = tokio::time::interval(self.settings.reload_interval);
+        // The first tick is immediate and the files were just loaded.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let modified = self.modified();
+            if modified == loaded {
+                continue;
+            }
+            match server_config(&self.settings) {
+                Ok(config) => {
+                    *self.current.write().unwrap() = Arc::new(config);
+                    loaded = modified;
+                    info!("reloaded TLS certificate");
+                }
+                Err(e) => error!("error reloading TLS certificate, keeping previous one {e}"),
+            }
+        }
+    }
+
+    /// Modification times of the files, to tell when they change.
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [
+            Some(&self.settings.cert_file),
+            Some(&self.settings.key_file),
+            self.settings.client_ca_file.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+    }
+}
+
+fn server_config(settings: &TlsSettings) -> Result<ServerConfig, String> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("unable to set up TLS {e}"))?;
+
+    let builder = match &settings.client_ca_file {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert).map_err(|e| {
+                    format!("{} has an unusable CA certificate: {e}", path.display())
+                })?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if settings.client_auth_required {
+                verifier
+            } else {
+                verifier.allow_unauthenticated()
+            };
+            builder.with_client_cert_verifier(
+                verifier
+                    .build()
+                    .map_err(|e| format!("unable to verify client certificates {e}"))?,
+            )
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let key = PrivateKeyDer::from_pem_file(&settings.key_file).map_err(|e| {
+        format!(
+            "{} is not a PEM private key: {e}",
+            settings.key_file.display()
+        )
+    })?;
+    let mut config = builder
+        .with_single_cert(load_certs(&settings.cert_file)?, key)
+        .map_err(|e| format!("TLS certificate and key don't make a pair: {e}"))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| {
+            format!(
+                "unable to read PEM certificates from {}: {e}",
+                path.display()
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(format!("{} has no PEM certificates", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Listener handing the server connections that have finished their TLS handshake.
+///
+/// Handshakes happen in their own tasks, so a slow client can't hold up everyone else's.
+pub struct TlsListener {
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, config: Arc<TlsConfig>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel(ACCEPTED_BACKLOG);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    // The server has stopped taking connections.
+                    _ = sender.closed() => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            // Like running out of file descriptors. Give it a moment.
+                            error!("error accepting connection {e}");
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            continue;
+                        }
+                    },
+                };
+                let acceptor = config.acceptor();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => debug!("TLS handshake with {addr} failed {e}"),
+                        Err(_) => debug!("TLS handshake with {addr} timed out"),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener {
+            accepted,
+            local_addr,
+        })
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accepting task only stops once this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
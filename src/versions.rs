@@ -0,0 +1,60 @@
+use axum::extract::Request;
+use axum::http::{header, HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Path prefix of version 1 of the API.
+pub const V1: &str = "/v1";
+
+pub static DEPRECATION_HEADER: HeaderName = HeaderName::from_static("deprecation");
+pub static SUNSET_HEADER: HeaderName = HeaderName::from_static("sunset");
+
+/// When the unversioned aliases were deprecated, as an RFC 9745 timestamp.
+static ALIASES_DEPRECATED: HeaderValue = HeaderValue::from_static("@1792281600");
+
+/// When the unversioned aliases may stop working, as an RFC 8594 HTTP date.
+static ALIASES_SUNSET: HeaderValue = HeaderValue::from_static("Sun, 18 Apr 2027 00:00:00 GMT");
+
+/// Middleware marking responses from the unversioned aliases of /v1 routes as deprecated.
+///
+/// Responses carry Deprecation and Sunset headers and link to the same path under /v1.
+pub async fn deprecated_alias(request: Request, next: Next) -> Response {
+    let successor = format!("<{V1}{}>; rel=\"successor-version\"", request.uri().path());
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(DEPRECATION_HEADER.clone(), ALIASES_DEPRECATED.clone());
+    headers.insert(SUNSET_HEADER.clone(), ALIASES_SUNSET.clone());
+    if let Ok(link) = HeaderValue::try_from(successor) {
+        headers.append(header::LINK, link);
+    }
+    response
+}
+
+/// A route pattern without its version prefix, like "/facilities/{uid}" for "/v1/facilities/{uid}".
+///
+/// Lets settings and limits keyed by route apply to every version of it, and to the unversioned aliases.
+pub fn unversioned(route: &str) -> &str {
+    let Some(rest) = route.strip_prefix("/v") else {
+        return route;
+    };
+    let rest_after_digits = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+    if rest_after_digits.len() < rest.len() && rest_after_digits.starts_with('/') {
+        rest_after_digits
+    } else {
+        route
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_version_prefix() {
+        assert_eq!(unversioned("/v1/facilities/{uid}"), "/facilities/{uid}");
+        assert_eq!(unversioned("/v12/facilities/"), "/facilities/");
+        assert_eq!(unversioned("/facilities/{uid}"), "/facilities/{uid}");
+        assert_eq!(unversioned("/vendors/{id}"), "/vendors/{id}");
+        assert_eq!(unversioned("/v/facilities"), "/v/facilities");
+    }
+}